@@ -21,5 +21,4 @@ rustPlatform.buildRustPackage {
 
   BLKID = lib.getExe' util-linux "blkid";
   BTRFS = lib.getExe' btrfs-progs "btrfs";
-  DISTRO_NAME = "NixOS";
 }
@@ -0,0 +1,371 @@
+use std::{
+	collections::VecDeque,
+	fs,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use eyre::{bail, eyre, Result, WrapErr};
+
+use crate::builder::Phase;
+
+#[derive(Clone, Debug)]
+pub struct Args {
+	pub command: Subcommand,
+	pub config_file: PathBuf,
+	pub default_config: PathBuf,
+	pub dry_run: bool,
+	pub verbose: bool,
+	/// Show progress while copying kernels and initrds, if stderr is a
+	/// terminal.
+	pub progress: bool,
+	/// Reinstall GRUB even if nothing changed since it was last installed, e.g.
+	/// after the boot sector got wiped.
+	pub force_reinstall: bool,
+	/// Only regenerate grub.cfg, never (re)install GRUB.
+	pub entries_only: bool,
+	/// Check the written grub.cfg with grub-script-check, restoring the
+	/// previous one if it doesn't parse.
+	pub check_config: bool,
+	/// Reinstall GRUB to removable media or into an image even when the inputs
+	/// haven't changed since the last run.
+	pub force: bool,
+	/// Upper bound on the number of kernels and initrds copied to /boot.
+	pub max_kernels: Option<usize>,
+	/// Where the target system's root is mounted, when installing GRUB into an
+	/// image rather than the running system. Everything is written under it,
+	/// grub-install included, while paths in grub.cfg are left alone.
+	pub image_root: Option<PathBuf>,
+	/// Where to write grub.cfg, instead of `<bootPath>/grub/grub.cfg`.
+	pub output: Option<PathBuf>,
+	/// Overrides the shell from the config, used to run shell snippets.
+	pub shell: Option<PathBuf>,
+	/// How many times to try grub-install before giving up.
+	pub install_attempts: u32,
+	/// Appended to `bootloaderId`, so several systems can share an ESP without
+	/// each needing its own config.
+	pub bootloader_id_suffix: Option<String>,
+	/// Forces installing GRUB to the removable media path, regardless of
+	/// `efiInstallAsRemovable`. Implies `no_nvram`.
+	pub removable: bool,
+	/// Forces leaving the EFI variables alone, regardless of
+	/// `canTouchEfiVariables`.
+	pub no_nvram: bool,
+	/// How long to let `extraPrepareConfig` run before killing it.
+	pub prepare_timeout: Option<Duration>,
+	/// How long to wait on reading a generation's metadata before skipping it.
+	pub read_timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Subcommand {
+	/// Generate grub.cfg and install GRUB. The default.
+	#[default]
+	Install,
+	/// Only print the fragment of grub.cfg generated by a single phase.
+	Render { phase: Option<Phase> },
+	/// Only print why GRUB would or wouldn't be reinstalled.
+	CompareState,
+	/// Only list every generation found, and why it's left off the menu if
+	/// it is.
+	ListGenerations,
+	/// Only print where grub.cfg would be written to.
+	PrintConfigPath,
+	/// Only print where GRUB's state would be kept.
+	PrintStatePath,
+	/// Only print the parsed config as JSON, with passwords left out.
+	DumpConfig,
+}
+impl Default for Args {
+	fn default() -> Self {
+		Self {
+			command: Subcommand::default(),
+			config_file: PathBuf::new(),
+			default_config: PathBuf::new(),
+			dry_run: false,
+			verbose: false,
+			progress: false,
+			force_reinstall: false,
+			entries_only: false,
+			check_config: false,
+			force: false,
+			max_kernels: None,
+			image_root: None,
+			output: None,
+			shell: None,
+			install_attempts: 1,
+			bootloader_id_suffix: None,
+			removable: false,
+			no_nvram: false,
+			prepare_timeout: None,
+			read_timeout: Duration::from_secs(30),
+		}
+	}
+}
+impl Args {
+	pub fn parse() -> Result<Self> {
+		Self::parse_from(std::env::args().skip(1))
+	}
+
+	/// Resolves a path on the target system to where it can be reached from
+	/// here, which differs when installing into a mounted image.
+	pub fn in_image(&self, path: &Path) -> PathBuf {
+		match &self.image_root {
+			Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+			None => path.to_owned(),
+		}
+	}
+
+	fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Self> {
+		let mut this = Self {
+			dry_run: std::env::var("DRY_RUN").as_deref() == Ok("true"),
+			..Default::default()
+		};
+
+		let mut args = args.into_iter().collect::<VecDeque<_>>();
+		let mut positional = vec![];
+
+		while let Some(arg) = args.pop_front() {
+			match arg.as_str() {
+				"--manifest" => {
+					let manifest = Manifest::load(next_value(&mut args, &arg)?)?;
+
+					// Splice the manifest's arguments in place of `--manifest <path>`,
+					// so they're parsed exactly like ones given on the command line.
+					for arg in manifest.into_args().into_iter().rev() {
+						args.push_front(arg);
+					}
+				}
+				"--dry-run" => this.dry_run = true,
+				"--verbose" => this.verbose = true,
+				"--force" => this.force = true,
+				"--entries-only" => this.entries_only = true,
+				"--force-reinstall" => this.force_reinstall = true,
+				"--progress" => this.progress = true,
+				"--check-config" => this.check_config = true,
+				"--removable" => this.removable = true,
+				"--no-nvram" => this.no_nvram = true,
+				"--compare-state" => {
+					this.command = Subcommand::CompareState;
+					this.dry_run = true;
+				}
+				"--print-config-path" => {
+					this.command = Subcommand::PrintConfigPath;
+					this.dry_run = true;
+				}
+				"--print-state-path" => {
+					this.command = Subcommand::PrintStatePath;
+					this.dry_run = true;
+				}
+				"--dump-config" => {
+					this.command = Subcommand::DumpConfig;
+					this.dry_run = true;
+				}
+				"--max-kernels" => {
+					let max = next_value(&mut args, &arg)?;
+					this.max_kernels = Some(
+						max.parse()
+							.with_context(|| format!("Invalid value for `{arg}`: {max}"))?,
+					);
+				}
+				// `--staging-root` is what it's called when building an image.
+				"--image-root" | "--staging-root" => {
+					this.image_root = Some(PathBuf::from(next_value(&mut args, &arg)?));
+				}
+				"--output" => {
+					this.output = Some(PathBuf::from(next_value(&mut args, &arg)?));
+				}
+				"--shell" => {
+					this.shell = Some(PathBuf::from(next_value(&mut args, &arg)?));
+				}
+				"--bootloader-id-suffix" => {
+					this.bootloader_id_suffix = Some(next_value(&mut args, &arg)?);
+				}
+				"--install-attempts" => {
+					let attempts = next_value(&mut args, &arg)?;
+					this.install_attempts = match attempts.parse() {
+						Ok(0) | Err(_) => {
+							bail!(
+								"Invalid value for `{arg}`: {attempts} - expected a positive \
+								 number"
+							)
+						}
+						Ok(n) => n,
+					};
+				}
+				"--read-timeout" => {
+					let secs = next_value(&mut args, &arg)?;
+					this.read_timeout = Duration::from_secs(
+						secs.parse()
+							.with_context(|| format!("Invalid value for `{arg}`: {secs}"))?,
+					);
+				}
+				"--prepare-timeout" => {
+					let secs = next_value(&mut args, &arg)?;
+					this.prepare_timeout =
+						Some(Duration::from_secs(secs.parse().with_context(|| {
+							format!("Invalid value for `{arg}`: {secs}")
+						})?));
+				}
+				"--phase" => {
+					let Subcommand::Render { phase } = &mut this.command else {
+						bail!("`--phase` is only valid for the `render` subcommand")
+					};
+					*phase = Some(next_value(&mut args, &arg)?.parse()?);
+				}
+				"render" if positional.is_empty() && this.command == Subcommand::Install => {
+					this.command = Subcommand::Render { phase: None };
+					// Rendering must never touch the disk.
+					this.dry_run = true;
+				}
+				"list-generations"
+					if positional.is_empty() && this.command == Subcommand::Install =>
+				{
+					this.command = Subcommand::ListGenerations;
+					this.dry_run = true;
+				}
+				flag if flag.starts_with("--") => bail!("Unknown flag `{flag}`"),
+				_ => positional.push(PathBuf::from(arg)),
+			}
+		}
+
+		let mut positional = positional.into_iter();
+		let Some(config_file) = positional.next() else {
+			bail!("Config file not given: expected it to be the first argument")
+		};
+		let Some(default_config) = positional.next() else {
+			bail!("Default config not given: expected it to be the second argument")
+		};
+		if let Some(extra) = positional.next() {
+			bail!("Unexpected extra argument `{}`", extra.display())
+		}
+
+		this.config_file = config_file;
+		this.default_config = default_config;
+		Ok(this)
+	}
+}
+
+/// An alternative to passing the arguments on the command line, for when the
+/// caller would rather hand us a single file.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+	config_file: String,
+	default_config: String,
+	#[serde(default)]
+	flags: Vec<String>,
+}
+impl Manifest {
+	fn load(path: String) -> Result<Self> {
+		let manifest =
+			fs::read_to_string(&path).with_context(|| format!("Cannot read manifest {path}"))?;
+		serde_json::from_str(&manifest).with_context(|| format!("Invalid manifest {path}"))
+	}
+
+	fn into_args(self) -> Vec<String> {
+		let mut args = vec![self.config_file, self.default_config];
+		args.extend(self.flags);
+		args
+	}
+}
+
+fn next_value(args: &mut VecDeque<String>, flag: &str) -> Result<String> {
+	args.pop_front()
+		.ok_or_else(|| eyre!("Flag `{flag}` expects a value"))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::*;
+
+	fn parse(args: &[&str]) -> Result<Args> {
+		Args::parse_from(args.iter().map(|&a| a.to_owned()))
+	}
+
+	#[test]
+	fn positional_arguments() {
+		let args = parse(&["config.xml", "/run/current-system"]).unwrap();
+		assert_eq!(args.config_file, Path::new("config.xml"));
+		assert_eq!(args.default_config, Path::new("/run/current-system"));
+
+		assert!(parse(&["config.xml"]).is_err());
+		assert!(parse(&["config.xml", "/run/current-system", "extra"]).is_err());
+	}
+
+	#[test]
+	fn manifest() {
+		let dir = tempfile::tempdir().unwrap();
+		let manifest = dir.path().join("manifest.json");
+		let manifest_arg = manifest.to_str().unwrap();
+
+		fs::write(
+			&manifest,
+			r#"{
+				"configFile": "config.xml",
+				"defaultConfig": "/run/current-system",
+				"flags": ["--dry-run"]
+			}"#,
+		)
+		.unwrap();
+		let args = parse(&["--manifest", manifest_arg]).unwrap();
+		assert_eq!(args.config_file, Path::new("config.xml"));
+		assert_eq!(args.default_config, Path::new("/run/current-system"));
+		assert!(args.dry_run);
+
+		fs::write(&manifest, r#"{ "configFile": "config.xml" }"#).unwrap();
+		assert!(parse(&["--manifest", manifest_arg]).is_err());
+		assert!(parse(&["--manifest"]).is_err());
+	}
+
+	#[test]
+	fn flags() {
+		let args = parse(&[
+			"--dry-run",
+			"config.xml",
+			"--max-kernels",
+			"4",
+			"/run/current-system",
+			"--image-root",
+			"/mnt",
+			"--install-attempts",
+			"3",
+			"--force",
+		])
+		.unwrap();
+		assert!(args.dry_run);
+		assert!(args.force);
+		assert_eq!(args.max_kernels, Some(4));
+		assert_eq!(args.image_root.as_deref(), Some(Path::new("/mnt")));
+		assert_eq!(args.in_image(Path::new("/boot")), Path::new("/mnt/boot"));
+		assert_eq!(args.install_attempts, 3);
+
+		assert!(parse(&["--install-attempts", "0", "config.xml", "/"]).is_err());
+		assert!(parse(&["--max-kernels"]).is_err());
+		assert!(parse(&["--frobnicate", "config.xml", "/"]).is_err());
+	}
+
+	#[test]
+	fn subcommands() {
+		let args = parse(&["render", "--phase", "entries", "config.xml", "/"]).unwrap();
+		assert_eq!(args.command, Subcommand::Render {
+			phase: Some(Phase::Entries)
+		});
+		assert!(args.dry_run);
+
+		let args = parse(&["--compare-state", "config.xml", "/"]).unwrap();
+		assert_eq!(args.command, Subcommand::CompareState);
+
+		let args = parse(&["list-generations", "config.xml", "/"]).unwrap();
+		assert_eq!(args.command, Subcommand::ListGenerations);
+		assert!(args.dry_run);
+
+		let args = parse(&["--dump-config", "config.xml", "/"]).unwrap();
+		assert_eq!(args.command, Subcommand::DumpConfig);
+		assert!(args.dry_run);
+
+		assert!(parse(&["--phase", "entries", "config.xml", "/"]).is_err());
+	}
+}
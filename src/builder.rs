@@ -1,70 +1,193 @@
 mod appearance;
 mod entries;
 mod install;
+#[cfg(test)]
+mod testing;
 
 use std::{
 	collections::HashSet,
+	ffi::OsStr,
 	fmt::Write as _,
 	fs,
-	os::unix::fs::PermissionsExt,
+	io::Write as _,
+	os::{linux::fs::MetadataExt, unix::fs::PermissionsExt},
 	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	str::FromStr,
 };
 
-use eyre::Result;
+use eyre::{bail, Context, Result};
+pub use install::{grub_cfg_path, state_path};
 
 use crate::{
+	args::Args,
 	config::{Config, Password},
 	grub::Grub,
 };
 
 pub struct Builder<'conf> {
 	config: Config<'conf>,
+	args: &'conf Args,
 
 	grub_boot: Grub,
 	grub_store: Option<Grub>,
+	/// Set when `<bootPath>/kernels`, where kernels and initrd secrets are
+	/// written to, is a separate filesystem from the boot path.
+	grub_secrets: Option<Grub>,
 
+	/// Where the boot path is written to, i.e. under the staging root if any.
+	boot_dir: PathBuf,
 	default_config: &'conf Path,
+	/// `bootloaderId` with the suffix from the command line, if any.
+	bootloader_id: String,
 	pub copied: HashSet<PathBuf>,
+	/// The fonts, splash images and theme files written to the boot path,
+	/// which are signed along with grub.cfg.
+	written: Vec<PathBuf>,
+	/// The submenus entries are currently being added to, outermost first.
+	submenus: Vec<String>,
+	/// Every generated menu entry, by the titles of the submenus it's in
+	/// followed by its own.
+	menu_entries: Vec<Vec<String>>,
+	kernel_copies: usize,
 
 	dry_run: bool,
 
 	inner: String,
 }
 impl<'conf> Builder<'conf> {
-	pub fn new(config: Config<'conf>, default_config: &'conf Path) -> Result<Self> {
-		let dry_run = std::env::var("DRY_RUN").as_deref() == Ok("true");
+	pub fn new(mut config: Config<'conf>, args: &'conf Args) -> Result<Self> {
+		let dry_run = args.dry_run;
 
-		let grub = config.boot_path.join("grub");
+		// Discover whether the bootPath is on the same filesystem as / and
+		// /nix/store.  If not, then all kernels and initrds must be copied to
+		// the bootPath. Both are looked up where they are in the image, if any.
+		let boot_dir = args.in_image(config.boot_path);
+		let store_dir = args.in_image(config.store_path);
+		let boot_dev = boot_dir.metadata()?.st_dev();
+		let store_dev = store_dir.metadata()?.st_dev();
+		if should_copy_kernels(boot_dev, store_dev) && !config.copy_kernels {
+			eprintln!(
+				"note: copying kernels and initrds to {}, as it is on a different filesystem than \
+				 {}",
+				config.boot_path.display(),
+				config.store_path.display()
+			);
+			config.copy_kernels = true;
+		}
+		// Only copied kernels and initrds get signed, as the store can't be written
+		// to.
+		if config.check_signatures && config.sign_config.is_some() && !config.copy_kernels {
+			bail!(
+				"`checkSignatures` needs `copyKernels`, so the kernels and initrds can be signed"
+			);
+		}
+
+		let grub = boot_dir.join("grub");
 
 		if !dry_run {
 			fs::create_dir_all(&grub)?;
 			fs::set_permissions(&grub, fs::Permissions::from_mode(0o700))?;
 		}
 
-		let grub_boot = Grub::new(config.boot_path, &config)?;
+		// All of them are looked up where they're written to, but as GRUB will
+		// find them once the image is booted.
+		let image_root = args.image_root.as_deref();
+		let grub_boot = Grub::new(&boot_dir, image_root, &config)?;
 		let grub_store = if !config.copy_kernels {
-			Some(Grub::new(config.store_path, &config)?)
+			Some(Grub::new(&store_dir, image_root, &config)?)
 		} else {
 			None
 		};
 
+		let kernels = boot_dir.join("kernels");
+		let grub_secrets = match kernels.metadata() {
+			Ok(m) if m.st_dev() != boot_dir.metadata()?.st_dev() => {
+				Some(Grub::new(&kernels, image_root, &config)?)
+			}
+			_ => None,
+		};
+
+		let bootloader_id = match &args.bootloader_id_suffix {
+			Some(suffix) => {
+				let id = format!("{}{suffix}", config.bootloader_id);
+				validate_bootloader_id(&id)?;
+				id
+			}
+			None => config.bootloader_id.to_owned(),
+		};
+
 		Ok(Self {
 			config,
+			args,
 			grub_boot,
 			grub_store,
-			default_config,
+			grub_secrets,
+			boot_dir,
+			default_config: &args.default_config,
+			bootloader_id,
 			dry_run,
 			copied: HashSet::new(),
+			written: vec![],
+			submenus: vec![],
+			menu_entries: vec![],
+			kernel_copies: 0,
 			inner: String::from("# Automatically generated.  DO NOT EDIT THIS FILE!\n\n"),
 		})
 	}
 
+	/// The grub.cfg generated by the phases run so far.
+	pub fn render(&self) -> &str {
+		&self.inner
+	}
+
+	/// The shell to run shell snippets with, which can be overridden from the
+	/// command line.
+	fn shell(&self) -> &Path {
+		self.args.shell.as_deref().unwrap_or(self.config.shell)
+	}
+
+	/// A command run with the tools of the target system on its `PATH`, as e.g.
+	/// `extraPrepareConfig` expects.
+	fn command(&self, program: impl AsRef<OsStr>) -> Command {
+		let mut cmd = Command::new(program);
+		cmd.env("PATH", self.config.path);
+		cmd
+	}
+
+	/// Runs a single phase against an empty buffer and returns what it
+	/// generated.
+	pub fn render_phase(&mut self, phase: Phase) -> Result<&str> {
+		self.inner.clear();
+
+		match phase {
+			Phase::Users => self.users()?,
+			Phase::DefaultEntry => self.default_entry()?,
+			Phase::Appearance => self.appearance()?,
+			Phase::Entries => self.entries()?,
+		};
+
+		Ok(&self.inner)
+	}
+
+	/// Substitutes the tokens users can refer to in the extra config snippets:
+	/// `@bootPath@` for the boot path as seen from the running system, and
+	/// `@bootRoot@` for it as seen by GRUB.
+	fn substitute(&self, s: &str) -> String {
+		s.replace("@bootPath@", &self.config.boot_path.to_string_lossy())
+			.replace("@bootRoot@", &self.grub_boot.path.to_string_lossy())
+	}
+
 	pub fn users(&mut self) -> Result<&mut Self> {
 		for (name, password) in self.config.users.0.iter() {
 			match password {
 				Password::Hashed(password) => {
 					writeln!(&mut self.inner, "password_pbkdf2 {name} {password}")?
 				}
+				Password::Plain(password) if self.config.hash_plaintext_passwords => {
+					let hash = self.hash_password(name, password)?;
+					writeln!(&mut self.inner, "password_pbkdf2 {name} {hash}")?
+				}
 				Password::Plain(password) => {
 					writeln!(&mut self.inner, "password {name} {password}")?
 				}
@@ -87,12 +210,64 @@ impl<'conf> Builder<'conf> {
 		Ok(self)
 	}
 
+	/// Hashes a plaintext password with grub-mkpasswd-pbkdf2, so that it
+	/// doesn't end up in grub.cfg as is.
+	fn hash_password(&self, user: &str, password: &str) -> Result<String> {
+		let Some(grub) = self.config.grub.or(self.config.grub_efi) else {
+			bail!("Cannot hash the password for user {user} without a GRUB package");
+		};
+
+		let mkpasswd = grub.join("bin/grub-mkpasswd-pbkdf2");
+		let mut child = self
+			.command(&mkpasswd)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()
+			.with_context(|| format!("Failed to execute {}", mkpasswd.display()))?;
+
+		// It asks for the password twice, to make sure it was typed correctly.
+		if let Some(mut stdin) = child.stdin.take() {
+			write!(stdin, "{password}\n{password}\n")?;
+		}
+
+		let output = child.wait_with_output()?;
+		if !output.status.success() {
+			bail!(
+				"{} failed to hash the password for user {user} ({})",
+				mkpasswd.display(),
+				output.status
+			);
+		}
+
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let Some(hash) = stdout
+			.split_whitespace()
+			.find(|s| s.starts_with("grub.pbkdf2"))
+		else {
+			bail!(
+				"{} didn't output a hash for user {user}",
+				mkpasswd.display()
+			);
+		};
+		Ok(hash.to_owned())
+	}
+
 	pub fn default_entry(&mut self) -> Result<&mut Self> {
 		// FIXME: should use grub-mkconfig.
+		// Anything but an index is the title of an entry, which is resolved to
+		// where in the menu it is once the entries are generated.
 		let default_entry = if self.config.save_default() {
-			r#""${saved_entry}""#
+			r#""${saved_entry}""#.to_owned()
+		} else if self
+			.config
+			.default_entry
+			.chars()
+			.all(|c| c.is_ascii_digit())
+		{
+			self.config.default_entry.to_owned()
 		} else {
-			self.config.default_entry
+			format!(r#""{}""#, escape_grub_string(self.config.default_entry))
 		};
 
 		let Config {
@@ -101,10 +276,39 @@ impl<'conf> Builder<'conf> {
 			..
 		} = &self.config;
 
+		// Only shows the menu if a key is pressed before the hidden timeout runs
+		// out, falling back to `sleep` on GRUBs without `timeout_style`. Not when
+		// booting a one-time entry though, which already skips the menu.
+		let hidden_timeout = match self.config.hidden_timeout {
+			Some(hidden_timeout) => format!(
+				r#"if [ -z "${{boot_once}}" ]; then
+  if [ x$feature_timeout_style = xy ]; then
+    set timeout_style=hidden
+    set timeout={hidden_timeout}
+  elif sleep --interruptible {hidden_timeout}; then
+    set timeout=0
+  fi
+fi
+"#
+			),
+			None => String::new(),
+		};
+
+		// Some firmware/GRUB builds don't reliably derive $prefix themselves, which
+		// breaks module loading. This has to come before any `insmod`.
+		let prefix = if self.config.explicit_prefix {
+			format!(
+				"set prefix={}\n",
+				self.grub_boot.path.join("grub").display()
+			)
+		} else {
+			String::new()
+		};
+
 		writeln!(
 			&mut self.inner,
 			r#"{search}
-if [ -s $prefix/grubenv ]; then
+{prefix}if [ -s $prefix/grubenv ]; then
   load_env
 fi
 
@@ -121,7 +325,7 @@ else
   set timeout={timeout}
 fi
 set timeout_style={timeout_style}
-
+{hidden_timeout}
 function savedefault {{
   if [ -z "${{boot_once}}"]; then
   saved_entry="${{chosen}}"
@@ -143,3 +347,172 @@ fi
 		Ok(self)
 	}
 }
+
+/// Escapes text to be put inside a double-quoted GRUB string, e.g. a menu
+/// entry title.
+fn escape_grub_string(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for c in s.chars() {
+		if matches!(c, '"' | '\\' | '$') {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+	escaped
+}
+
+/// Makes sure a bootloader ID is usable both as a directory name on the ESP
+/// and as an NVRAM boot entry label.
+fn validate_bootloader_id(id: &str) -> Result<()> {
+	if id.is_empty() {
+		bail!("Bootloader ID must not be empty");
+	}
+	if let Some(c) = id
+		.chars()
+		.find(|&c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+	{
+		bail!(
+			"Bootloader ID `{id}` contains `{c}`: only ASCII letters, digits, `-`, `_` and `.` \
+			 are allowed"
+		);
+	}
+	Ok(())
+}
+
+/// The phases of grub.cfg generation, in the order they're normally run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+	Users,
+	DefaultEntry,
+	Appearance,
+	Entries,
+}
+impl FromStr for Phase {
+	type Err = eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"users" => Ok(Self::Users),
+			"default-entry" => Ok(Self::DefaultEntry),
+			"appearance" => Ok(Self::Appearance),
+			"entries" => Ok(Self::Entries),
+			s => bail!(
+				"Unknown phase `{s}`: expected one of users, default-entry, appearance or entries"
+			),
+		}
+	}
+}
+
+/// Whether kernels and initrds have to be copied to the boot path, as it's on
+/// a different filesystem than the store, which GRUB then can't reach. Takes
+/// the device numbers of both.
+fn should_copy_kernels(boot_dev: u64, store_dev: u64) -> bool {
+	boot_dev != store_dev
+}
+
+#[cfg(test)]
+mod tests {
+	use roxmltree::Document;
+
+	use super::*;
+	use crate::builder::testing::{boolean, string, Fixture};
+
+	#[test]
+	fn copies_kernels_only_across_filesystems() {
+		assert!(!should_copy_kernels(2049, 2049));
+		assert!(should_copy_kernels(2049, 2050));
+	}
+
+	#[test]
+	fn hashes_plaintext_passwords() {
+		let mut fx = Fixture::new();
+		fx.set(
+			"users",
+			String::from(
+				"<attrs><attr name=\"root\"><attrs><attr name=\"password\"><string \
+				 value=\"hunter2\" /></attr></attrs></attr></attrs>",
+			),
+		);
+
+		let users = |fx: &Fixture| {
+			let xml = fx.xml();
+			let doc = Document::parse(&xml).unwrap();
+			let config = Config::new(&doc).unwrap();
+			let mut builder = fx.builder(config);
+			builder.users().unwrap();
+			builder.inner
+		};
+
+		assert!(users(&fx).contains("password root hunter2\n"));
+
+		fx.set("hashPlaintextPasswords", boolean(true));
+		let users = users(&fx);
+		assert!(users.contains("password_pbkdf2 root grub.pbkdf2.sha512.10000.AB.CD\n"));
+		assert!(!users.contains("hunter2"));
+		// It's asked for twice.
+		let input = fs::read_to_string(fx.root().join("mkpasswd-input")).unwrap();
+		assert_eq!(input, "hunter2\nhunter2\n");
+	}
+
+	#[test]
+	fn looks_up_filesystems_in_the_image() {
+		let mut fx = Fixture::new();
+		// Neither exists outside of the image.
+		fx.set("bootPath", string("/boot"));
+		fx.set("storePath", string("/store"));
+		fx.args.image_root = Some(fx.root().to_owned());
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		let builder = Builder::new(config, &fx.args).unwrap();
+
+		let grub_store = builder.grub_store.as_ref().unwrap();
+		for (grub, dir) in [(&builder.grub_boot, "boot"), (grub_store, "store")] {
+			// The filesystem is searched for if it can be, and paths are relative to
+			// `$root` otherwise.
+			assert_eq!(grub.search.is_empty(), grub.path.is_relative());
+			assert!(grub.search.is_empty() || grub.search.starts_with("search --set=drive"));
+			// Relative to the image, not wherever it's found from here.
+			let skip = usize::from(!grub.search.is_empty());
+			assert_eq!(
+				grub.path.iter().skip(skip).collect::<PathBuf>(),
+				Path::new(dir)
+			);
+		}
+	}
+
+	#[test]
+	fn writes_under_the_staging_root() {
+		let mut fx = Fixture::new();
+		fx.set("bootPath", string("/boot"));
+		fx.set("storePath", string("/store"));
+		fx.set("copyKernels", boolean(true));
+		fx.args.image_root = Some(fx.root().to_owned());
+		fx.args.entries_only = true;
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		Builder::new(config, &fx.args)
+			.unwrap()
+			.default_entry()
+			.unwrap()
+			.entries()
+			.unwrap()
+			.install()
+			.unwrap();
+
+		let kernels = fs::read_dir(fx.root().join("boot/kernels"))
+			.unwrap()
+			.count();
+		assert_eq!(kernels, 2);
+		let cfg = fx.grub_cfg();
+		let linux = cfg
+			.lines()
+			.find(|l| l.trim_start().starts_with("linux "))
+			.unwrap();
+		let kernel = linux.split_whitespace().nth(1).unwrap();
+		assert!(kernel.starts_with("boot/kernels/") || kernel.contains(")/boot/kernels/"));
+	}
+}
@@ -1,6 +1,13 @@
-use std::{collections::HashSet, fmt::Write as _, fs, path::PathBuf};
+use std::{
+	collections::{hash_map::DefaultHasher, BTreeSet},
+	fmt::Write as _,
+	fs,
+	hash::{Hash, Hasher},
+	path::{Path, PathBuf},
+};
 
 use eyre::{bail, Result, WrapErr};
+use rayon::prelude::*;
 use walkdir::WalkDir;
 
 use super::Builder;
@@ -8,18 +15,64 @@ use crate::config::Config;
 
 impl Builder<'_> {
 	pub fn appearance(&mut self) -> Result<&mut Self> {
+		// Changes to the font, splash or theme files themselves don't otherwise show
+		// up in grub.cfg, so record a fingerprint of them to make them visible in
+		// a diff of the generated config.
+		let hash = self.appearance_hash()?;
+		writeln!(&mut self.inner, "# Appearance inputs: {hash:016x}\n")?;
+
 		self.append_font()?;
 		self.append_splash()?;
+		self.append_colors()?;
 		self.append_theme()?;
 		self.append_extra_config()?;
 
 		Ok(self)
 	}
 
+	pub(super) fn appearance_hash(&self) -> Result<u64> {
+		let Config {
+			font,
+			splash_image,
+			splash_image_efi,
+			splash_image_bios,
+			splash_mode,
+			background_color,
+			theme,
+			..
+		} = &self.config;
+
+		let mut hasher = DefaultHasher::new();
+
+		for font in &font.0 {
+			hash_file(&mut hasher, font)?;
+		}
+		for image in [splash_image, splash_image_efi, splash_image_bios]
+			.into_iter()
+			.flatten()
+		{
+			hash_file(&mut hasher, image)?;
+		}
+		splash_mode.hash(&mut hasher);
+		background_color.hash(&mut hasher);
+
+		if let Some(theme) = theme {
+			let theme = theme_root(theme)?;
+			for entry in WalkDir::new(theme).sort_by_file_name() {
+				let entry = entry?;
+				if entry.file_type().is_file() {
+					entry.path().strip_prefix(theme)?.hash(&mut hasher);
+					hash_file(&mut hasher, entry.path())?;
+				}
+			}
+		}
+
+		Ok(hasher.finish())
+	}
+
 	pub fn append_font(&mut self) -> Result<()> {
 		let Config {
 			font,
-			boot_path,
 			gfx_mode_efi,
 			gfx_payload_efi,
 			gfx_mode_bios,
@@ -27,18 +80,56 @@ impl Builder<'_> {
 			..
 		} = &self.config;
 
-		let font_path = boot_path.join("converted-font.pf2");
-		if !self.dry_run {
-			fs::copy(font, font_path).with_context(|| {
-				format!("Cannot copy {} to {}", font.display(), boot_path.display())
-			})?;
+		validate_gfx_payload(gfx_payload_efi).context("Invalid `gfxpayloadEfi`")?;
+		validate_gfx_payload(gfx_payload_bios).context("Invalid `gfxpayloadBios`")?;
+
+		// gfxterm can't work without at least one font.
+		if font.0.is_empty() {
+			return Ok(());
 		}
 
+		let mut loads = vec![];
+		for (i, font) in font.0.iter().enumerate() {
+			// The first font keeps its historical name.
+			let name = if i == 0 {
+				"converted-font.pf2".to_owned()
+			} else {
+				format!("converted-font-{i}.pf2")
+			};
+
+			let font_path = self.boot_dir.join(&name);
+			if !self.dry_run {
+				if font.extension().is_some_and(|e| e == "pf2") {
+					fs::copy(font, &font_path).with_context(|| {
+						format!(
+							"Cannot copy {} to {}",
+							font.display(),
+							self.boot_dir.display()
+						)
+					})?;
+				} else {
+					self.convert_font(font, &font_path)?;
+				}
+				self.written.push(font_path);
+			}
+
+			loads.push(format!(
+				"loadfont {}",
+				self.grub_boot.path.join(name).display()
+			));
+		}
+
+		// Only the first font decides whether gfxterm is usable; the others are
+		// extra sizes for e.g. HiDPI displays.
+		let mut loads = loads.into_iter();
+		let first = loads.next().unwrap_or_default();
+		let rest = loads.map(|l| format!("  {l}\n")).collect::<String>();
+
 		writeln!(
 			&mut self.inner,
 			r#"insmod font
-if loadfont {font}; then
-  insmod gfxterm
+if {first}; then
+{rest}  insmod gfxterm
   if [ "${{grub_platform}}" = "efi" ]; then
     set gfxmode={gfx_mode_efi}
     set gfxpayload={gfx_payload_efi}
@@ -49,107 +140,275 @@ if loadfont {font}; then
   terminal_output gfxterm
 fi
 "#,
-			font = self.grub_boot.path.join("convert-font.pf2").display(),
 		)?;
 
 		Ok(())
 	}
 
+	/// Converts a TrueType/OpenType font into GRUB's own PF2 format.
+	fn convert_font(&self, font: &Path, target: &Path) -> Result<()> {
+		let Some(grub) = self.config.grub.or(self.config.grub_efi) else {
+			bail!(
+				"Cannot convert {} to PF2 without a GRUB package",
+				font.display()
+			);
+		};
+
+		let mkfont = grub.join("bin/grub-mkfont");
+		let status = self
+			.command(&mkfont)
+			.arg(format!("--output={}", target.display()))
+			.arg(format!("--size={}", self.config.font_size))
+			.arg(font)
+			.status()
+			.with_context(|| format!("Failed to execute {}", mkfont.display()))?;
+
+		if !status.success() {
+			bail!(
+				"{}: conversion of {} failed: ({status})",
+				mkfont.display(),
+				font.display()
+			);
+		}
+
+		Ok(())
+	}
+
 	pub fn append_splash(&mut self) -> Result<()> {
 		let Config {
 			splash_image,
+			splash_image_efi,
+			splash_image_bios,
 			background_color,
-			boot_path,
 			splash_mode,
 			..
 		} = &self.config;
 
-		let Some(splash_image) = splash_image else {
-			return Ok(());
-		};
-
-		let mut target = PathBuf::from("background");
+		let efi_image = splash_image_efi.or(*splash_image);
+		let bios_image = splash_image_bios.or(*splash_image);
 
-		let ext = if let Some(ext) = splash_image.extension() {
-			if ext == "jpg" {
-				"jpeg".into()
-			} else {
-				ext.to_string_lossy()
-			}
-		} else {
-			bail!("Splash image has no extension - could not decide which module to load!");
-		};
-
-		target.set_extension(ext.as_ref());
+		if efi_image.is_none() && bios_image.is_none() {
+			return Ok(());
+		}
 
 		if let Some(background_color) = background_color {
+			validate_color(background_color).context("Invalid `backgroundColor`")?;
 			writeln!(&mut self.inner, "background_color '{background_color}'")?;
 		}
 
-		if !self.dry_run {
-			fs::copy(splash_image, boot_path.join(&target)).with_context(|| {
-				format!(
-					"Cannot copy {} to {}",
-					splash_image.display(),
-					boot_path.display()
-				)
-			})?;
+		let splash_mode = splash_mode.as_str();
+
+		if let (None, None, Some(splash_image)) =
+			(splash_image_efi, splash_image_bios, splash_image)
+		{
+			let (module, target) = self.copy_splash(splash_image, "background")?;
+
+			writeln!(
+				&mut self.inner,
+				r#"insmod {module}
+if background_image --mode '{splash_mode}' {target}; then
+  set splash_loaded=true
+fi
+"#,
+				target = target.display(),
+			)?;
+
+			return Ok(());
 		}
 
-		let splash_mode = splash_mode.unwrap_or_default();
+		// The images differ between firmware types, so pick the right one at boot
+		// time.
+		let load_efi = self.splash_loader(efi_image, "background-efi", splash_mode)?;
+		let load_bios = self.splash_loader(bios_image, "background-bios", splash_mode)?;
+
 		writeln!(
 			&mut self.inner,
-			r#"insmod {ext}
-if background_image --mode '{splash_mode}' {target}; then
-  set color_normal=white/black
-  set color_highlight=black/white
+			r#"if [ "${{grub_platform}}" = "efi" ]; then
+{load_efi}
 else
-  set menu_color_normal=cyan/blue
-  set menu_color_highlight=white/blue
+{load_bios}
 fi
-"#,
-			target = self.grub_boot.path.join(target).display(),
+"#
 		)?;
 
 		Ok(())
 	}
 
-	pub fn append_theme(&mut self) -> Result<()> {
+	/// Sets the text colors, depending on whether `append_splash` managed to
+	/// load a background image. Runs even without a splash, so text mode gets
+	/// colors too.
+	pub fn append_colors(&mut self) -> Result<()> {
 		let Config {
-			boot_path, theme, ..
+			splash_image,
+			splash_image_efi,
+			splash_image_bios,
+			color_normal,
+			color_highlight,
+			menu_color_normal,
+			menu_color_highlight,
+			..
 		} = &self.config;
-		let theme_dir = boot_path.join("theme");
 
-		if !self.dry_run && theme_dir.exists() {
-			fs::remove_dir_all(&theme_dir).with_context(|| {
-				format!("Cannot clean up theme folder in {}", boot_path.display())
+		let color_normal = color_normal.unwrap_or("white/black");
+		let color_highlight = color_highlight.unwrap_or("black/white");
+		let menu_color_normal = menu_color_normal.unwrap_or("cyan/blue");
+		let menu_color_highlight = menu_color_highlight.unwrap_or("white/blue");
+
+		// Without a splash there's nothing to fall back from, so the menu colors
+		// always apply.
+		if splash_image
+			.or(*splash_image_efi)
+			.or(*splash_image_bios)
+			.is_none()
+		{
+			writeln!(
+				&mut self.inner,
+				"set menu_color_normal={menu_color_normal}\nset \
+				 menu_color_highlight={menu_color_highlight}\n"
+			)?;
+			return Ok(());
+		}
+
+		writeln!(
+			&mut self.inner,
+			r#"if [ "${{splash_loaded}}" ]; then
+  set color_normal={color_normal}
+  set color_highlight={color_highlight}
+else
+  set menu_color_normal={menu_color_normal}
+  set menu_color_highlight={menu_color_highlight}
+fi
+"#,
+		)?;
+
+		Ok(())
+	}
+
+	fn splash_loader(&mut self, image: Option<&Path>, name: &str, mode: &str) -> Result<String> {
+		let Some(image) = image else {
+			return Ok("  set splash_loaded=".to_owned());
+		};
+
+		let (module, target) = self.copy_splash(image, name)?;
+
+		Ok(format!(
+			r#"  insmod {module}
+  if background_image --mode '{mode}' {target}; then
+    set splash_loaded=true
+  fi"#,
+			target = target.display(),
+		))
+	}
+
+	/// Copies a splash image to `/boot/<name>.<ext>`, returning the module
+	/// needed to load it and its path as seen by GRUB.
+	fn copy_splash(&mut self, image: &Path, name: &str) -> Result<(&'static str, PathBuf)> {
+		let boot_dir = &self.boot_dir;
+		let mut target = PathBuf::from(name);
+
+		let Some(module) = image
+			.extension()
+			.and_then(|e| e.to_str())
+			.and_then(image_module)
+		else {
+			bail!(
+				"Unsupported splash image {} - supported formats are {SUPPORTED_IMAGE_FORMATS}",
+				image.display(),
+			);
+		};
+
+		// Use the module name as the extension so the copied file is named
+		// consistently, regardless of e.g. `.jpg` vs `.JPEG`.
+		target.set_extension(module);
+
+		if !self.dry_run {
+			let copy = boot_dir.join(&target);
+			fs::copy(image, &copy).with_context(|| {
+				format!("Cannot copy {} to {}", image.display(), boot_dir.display())
 			})?;
+			self.written.push(copy);
 		}
 
-		let Some(theme) = theme else {
-			return Ok(());
+		Ok((module, self.grub_boot.path.join(target)))
+	}
+
+	pub fn append_theme(&mut self) -> Result<()> {
+		let theme_dir = self.boot_dir.join("theme");
+		let clean_up = || -> Result<()> {
+			if !self.dry_run && theme_dir.exists() {
+				fs::remove_dir_all(&theme_dir).with_context(|| {
+					format!(
+						"Cannot clean up theme folder in {}",
+						self.boot_dir.display()
+					)
+				})?;
+			}
+			Ok(())
 		};
 
-		let mut modules_to_load = HashSet::new();
+		let Some(theme) = self.config.theme else {
+			return clean_up();
+		};
+		let theme = theme_root(theme)?;
+
+		// Sorted, so that the generated config doesn't depend on directory order.
+		let entries = WalkDir::new(theme)
+			.sort_by_file_name()
+			.into_iter()
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let mut modules_to_load = BTreeSet::new();
 		let mut fonts = vec![];
+		let mut has_theme_txt = false;
 
-		// TODO: Could be parallelized
-		for entry in WalkDir::new(theme) {
-			let entry = entry?;
+		for entry in &entries {
 			let relative = entry.path().strip_prefix(theme)?;
 
+			if relative == Path::new("theme.txt") {
+				has_theme_txt = true;
+			}
+
 			if let Some(ext) = entry.path().extension().and_then(|e| e.to_str()) {
-				match ext {
-					"png" => _ = modules_to_load.insert("png"),
-					"jpeg" | "jpg" => _ = modules_to_load.insert("jpeg"),
-					"pf2" => fonts.push(relative.to_owned()),
-					_ => {}
+				if let Some(module) = image_module(ext) {
+					modules_to_load.insert(module);
+				} else if ext == "pf2" {
+					fonts.push(relative.to_owned());
 				}
 			}
+		}
 
-			if !self.dry_run {
-				fs::copy(entry.path(), theme_dir.join(relative))?;
-			}
+		// Checked before touching /boot, so the installed theme is kept as is.
+		if !has_theme_txt {
+			bail!(
+				"Theme {} does not contain a theme.txt - GRUB would silently fall back to text \
+				 mode. Point `theme` at the directory containing theme.txt.",
+				theme.display()
+			);
+		}
+
+		clean_up()?;
+
+		// Copying is what's slow on a cold /boot, so do that in parallel.
+		if !self.dry_run {
+			let copies = entries
+				.par_iter()
+				.filter(|entry| !entry.file_type().is_dir())
+				.map(|entry| -> Result<PathBuf> {
+					let target = theme_dir.join(entry.path().strip_prefix(theme)?);
+					if let Some(parent) = target.parent() {
+						fs::create_dir_all(parent)?;
+					}
+					fs::copy(entry.path(), &target).with_context(|| {
+						format!(
+							"Cannot copy {} to {}",
+							entry.path().display(),
+							target.display()
+						)
+					})?;
+					Ok(target)
+				})
+				.collect::<Result<Vec<_>>>()?;
+			self.written.extend(copies);
 		}
 
 		for module in modules_to_load {
@@ -168,9 +427,26 @@ export theme
 			boot_font_path.join("theme.txt").display(),
 		)?;
 
+		// Like the console font, a font that fails to load shouldn't go unnoticed.
 		for font in fonts {
+			let source = theme.join(&font);
+			if fs::metadata(&source)?.len() == 0 {
+				eprintln!(
+					"warning: theme font {} is empty, GRUB will fail to load it",
+					source.display()
+				);
+			}
+
 			boot_font_path.push(font);
-			write!(&mut self.inner, "loadfont {}", boot_font_path.display())?;
+			writeln!(
+				&mut self.inner,
+				r#"if loadfont {font}; then
+  true
+else
+  echo "warning: cannot load theme font {font}"
+fi"#,
+				font = boot_font_path.display(),
+			)?;
 			boot_font_path.pop();
 		}
 
@@ -178,7 +454,384 @@ export theme
 	}
 
 	pub fn append_extra_config(&mut self) -> Result<()> {
-		writeln!(&mut self.inner, "{}\n", self.config.extra_config)?;
+		let extra_config = self.substitute(self.config.extra_config);
+		writeln!(&mut self.inner, "{extra_config}\n")?;
 		Ok(())
 	}
 }
+
+/// Returns the directory containing the theme. Pointing `theme` at the
+/// theme.txt itself is accepted as well, as it's an easy mistake to make.
+fn theme_root(theme: &Path) -> Result<&Path> {
+	if !theme.is_file() {
+		return Ok(theme);
+	}
+
+	match (theme.file_name(), theme.parent()) {
+		(Some(name), Some(parent)) if name == "theme.txt" => Ok(parent),
+		_ => bail!(
+			"Theme {} is a file, not a directory - `theme` should point at the directory \
+			 containing theme.txt",
+			theme.display()
+		),
+	}
+}
+
+/// Colors GRUB's video subsystem knows by name, which are the SVG ones.
+const NAMED_COLORS: &[&str] = &[
+	"aliceblue",
+	"antiquewhite",
+	"aqua",
+	"aquamarine",
+	"azure",
+	"beige",
+	"bisque",
+	"black",
+	"blanchedalmond",
+	"blue",
+	"blueviolet",
+	"brown",
+	"burlywood",
+	"cadetblue",
+	"chartreuse",
+	"chocolate",
+	"coral",
+	"cornflowerblue",
+	"cornsilk",
+	"crimson",
+	"cyan",
+	"darkblue",
+	"darkcyan",
+	"darkgoldenrod",
+	"darkgray",
+	"darkgreen",
+	"darkgrey",
+	"darkkhaki",
+	"darkmagenta",
+	"darkolivegreen",
+	"darkorange",
+	"darkorchid",
+	"darkred",
+	"darksalmon",
+	"darkseagreen",
+	"darkslateblue",
+	"darkslategray",
+	"darkslategrey",
+	"darkturquoise",
+	"darkviolet",
+	"deeppink",
+	"deepskyblue",
+	"dimgray",
+	"dimgrey",
+	"dodgerblue",
+	"firebrick",
+	"floralwhite",
+	"forestgreen",
+	"fuchsia",
+	"gainsboro",
+	"ghostwhite",
+	"gold",
+	"goldenrod",
+	"gray",
+	"grey",
+	"green",
+	"greenyellow",
+	"honeydew",
+	"hotpink",
+	"indianred",
+	"indigo",
+	"ivory",
+	"khaki",
+	"lavender",
+	"lavenderblush",
+	"lawngreen",
+	"lemonchiffon",
+	"lightblue",
+	"lightcoral",
+	"lightcyan",
+	"lightgoldenrodyellow",
+	"lightgray",
+	"lightgreen",
+	"lightgrey",
+	"lightpink",
+	"lightsalmon",
+	"lightseagreen",
+	"lightskyblue",
+	"lightslategray",
+	"lightslategrey",
+	"lightsteelblue",
+	"lightyellow",
+	"lime",
+	"limegreen",
+	"linen",
+	"magenta",
+	"maroon",
+	"mediumaquamarine",
+	"mediumblue",
+	"mediumorchid",
+	"mediumpurple",
+	"mediumseagreen",
+	"mediumslateblue",
+	"mediumspringgreen",
+	"mediumturquoise",
+	"mediumvioletred",
+	"midnightblue",
+	"mintcream",
+	"mistyrose",
+	"moccasin",
+	"navajowhite",
+	"navy",
+	"oldlace",
+	"olive",
+	"olivedrab",
+	"orange",
+	"orangered",
+	"orchid",
+	"palegoldenrod",
+	"palegreen",
+	"paleturquoise",
+	"palevioletred",
+	"papayawhip",
+	"peachpuff",
+	"peru",
+	"pink",
+	"plum",
+	"powderblue",
+	"purple",
+	"red",
+	"rosybrown",
+	"royalblue",
+	"saddlebrown",
+	"salmon",
+	"sandybrown",
+	"seagreen",
+	"seashell",
+	"sienna",
+	"silver",
+	"skyblue",
+	"slateblue",
+	"slategray",
+	"slategrey",
+	"snow",
+	"springgreen",
+	"steelblue",
+	"tan",
+	"teal",
+	"thistle",
+	"tomato",
+	"turquoise",
+	"violet",
+	"wheat",
+	"white",
+	"whitesmoke",
+	"yellow",
+	"yellowgreen",
+];
+
+/// Checks that GRUB will understand a color, as it silently falls back to black
+/// otherwise. Mirrors `grub_video_parse_color`: `#RGB`, `#RGBA`, `#RRGGBB` or
+/// `#RRGGBBAA` in hex, `r,g,b` or `r,g,b,a` in decimal, or a name.
+fn validate_color(color: &str) -> Result<()> {
+	if let Some(hex) = color.strip_prefix('#') {
+		if matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+			return Ok(());
+		}
+		bail!("`{color}` is not a valid hex color - expected #RGB, #RGBA, #RRGGBB or #RRGGBBAA");
+	}
+
+	if color.contains(',') {
+		let components = color
+			.split(',')
+			.map(|c| c.trim().parse::<u8>())
+			.collect::<Vec<_>>();
+		if matches!(components.len(), 3 | 4) && components.iter().all(Result::is_ok) {
+			return Ok(());
+		}
+		bail!("`{color}` is not a valid color - expected r,g,b or r,g,b,a, each from 0 to 255");
+	}
+
+	if NAMED_COLORS.contains(&color) {
+		return Ok(());
+	}
+
+	bail!(
+		"`{color}` is not a valid color - expected #RRGGBB, r,g,b or an SVG color name like \
+		 `black` or `darkslategray`"
+	);
+}
+
+/// Checks that GRUB will understand a `gfxpayload`, as it boots the kernel in
+/// text mode otherwise, e.g. `keep`, `text`, or `1920x1080` optionally with a
+/// depth. Like `gfxmode`, several resolutions to try in order may be given,
+/// separated by `,` or `;`, with `auto` for whatever the firmware prefers.
+fn validate_gfx_payload(payload: &str) -> Result<()> {
+	if matches!(payload, "keep" | "text") {
+		return Ok(());
+	}
+
+	let is_resolution = |mode: &str| {
+		let parts = mode.split('x').collect::<Vec<_>>();
+		matches!(parts.len(), 2 | 3)
+			&& parts
+				.iter()
+				.all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+	};
+	if payload
+		.split([',', ';'])
+		.all(|mode| mode == "auto" || is_resolution(mode))
+	{
+		return Ok(());
+	}
+
+	bail!("`{payload}` is not a valid gfxpayload - expected keep, text, or WIDTHxHEIGHT[xDEPTH]");
+}
+
+const SUPPORTED_IMAGE_FORMATS: &str = "png, jpg/jpeg and tga";
+
+/// Returns the GRUB module needed to load an image with the given extension.
+///
+/// Shared between splash images and themes, so both support the same formats.
+fn image_module(ext: &str) -> Option<&'static str> {
+	match ext.to_ascii_lowercase().as_str() {
+		"png" => Some("png"),
+		"jpg" | "jpeg" => Some("jpeg"),
+		"tga" => Some("tga"),
+		_ => None,
+	}
+}
+
+fn hash_file(hasher: &mut impl Hasher, path: &Path) -> Result<()> {
+	path.hash(hasher);
+	fs::read(path)
+		.with_context(|| format!("Cannot read {}", path.display()))?
+		.hash(hasher);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use roxmltree::Document;
+
+	use super::*;
+	use crate::builder::testing::{string, Fixture};
+
+	#[test]
+	fn gfx_payloads() {
+		for payload in ["keep", "text", "1920x1080", "1920x1080x32", "1024x768,auto"] {
+			validate_gfx_payload(payload).unwrap();
+		}
+		for payload in ["", "1920", "1920x", "1920x1080x32x1", "big"] {
+			assert!(validate_gfx_payload(payload).is_err());
+		}
+	}
+
+	#[test]
+	fn image_modules() {
+		assert_eq!(image_module("png"), Some("png"));
+		assert_eq!(image_module("JPG"), Some("jpeg"));
+		assert_eq!(image_module("jpeg"), Some("jpeg"));
+		assert_eq!(image_module("tga"), Some("tga"));
+		assert_eq!(image_module("bmp"), None);
+	}
+
+	#[test]
+	fn theme_roots() {
+		let dir = tempfile::tempdir().unwrap();
+		let theme = dir.path().join("theme");
+		fs::create_dir(&theme).unwrap();
+		fs::write(theme.join("theme.txt"), "").unwrap();
+		fs::write(theme.join("background.png"), "").unwrap();
+
+		assert_eq!(theme_root(&theme).unwrap(), theme);
+		assert_eq!(theme_root(&theme.join("theme.txt")).unwrap(), theme);
+		assert!(theme_root(&theme.join("background.png")).is_err());
+	}
+
+	#[test]
+	fn hex_colors() {
+		for color in ["#fff", "#ffff", "#1e1e2e", "#1e1e2e80"] {
+			validate_color(color).unwrap();
+		}
+		assert!(validate_color("#12345").is_err());
+		assert!(validate_color("#ggg").is_err());
+	}
+
+	#[test]
+	fn decimal_colors() {
+		validate_color("30,30,46").unwrap();
+		validate_color("30, 30, 46, 128").unwrap();
+		assert!(validate_color("30,30").is_err());
+		assert!(validate_color("30,30,256").is_err());
+	}
+
+	#[test]
+	fn named_colors() {
+		validate_color("darkslategray").unwrap();
+		validate_color("black").unwrap();
+		// Those are terminal colors, which `background_color` doesn't take.
+		assert!(validate_color("light-gray").is_err());
+		assert!(validate_color("blakc").is_err());
+	}
+
+	#[test]
+	fn guards_theme_fonts() {
+		let mut fx = Fixture::new();
+		let theme = fx.root().join("theme");
+		fs::create_dir_all(theme.join("fonts")).unwrap();
+		fs::write(theme.join("theme.txt"), "").unwrap();
+		fs::write(theme.join("fonts/unifont.pf2"), "PFF2").unwrap();
+		fx.set("theme", string(&theme.to_string_lossy()));
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		let mut builder = fx.builder(config);
+		builder.appearance().unwrap();
+
+		let font = builder.grub_boot.path.join("theme/fonts/unifont.pf2");
+		assert!(builder.inner.contains(&format!(
+			"if loadfont {font}; then\n  true\nelse\n  echo \"warning: cannot load theme font \
+			 {font}\"\nfi",
+			font = font.display()
+		)));
+	}
+
+	#[test]
+	fn sets_colors_without_a_splash() {
+		let mut fx = Fixture::new();
+		fx.set("menuColorNormal", string("light-gray/black"));
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		let mut builder = fx.builder(config);
+		builder.appearance().unwrap();
+
+		assert!(builder
+			.inner
+			.contains("set menu_color_normal=light-gray/black\n"));
+		assert!(builder
+			.inner
+			.contains("set menu_color_highlight=white/blue\n"));
+		assert!(!builder.inner.contains("splash_loaded"));
+	}
+
+	#[test]
+	fn sets_colors_depending_on_the_splash() {
+		let mut fx = Fixture::new();
+		let splash = fx.root().join("splash.png");
+		fs::write(&splash, "").unwrap();
+		fx.set("splashImage", string(&splash.to_string_lossy()));
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		let mut builder = fx.builder(config);
+		builder.appearance().unwrap();
+
+		assert!(builder.inner.contains(
+			"if [ \"${splash_loaded}\" ]; then\n  set color_normal=white/black\n  set \
+			 color_highlight=black/white\nelse\n  set menu_color_normal=cyan/blue\n"
+		));
+	}
+}
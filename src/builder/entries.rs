@@ -2,43 +2,139 @@ use std::{
 	cmp::Reverse,
 	fmt::Write as _,
 	fs,
+	io::{self, IsTerminal, Read, Write as _},
 	os::unix::fs::PermissionsExt,
 	path::{Path, PathBuf},
-	process::Command,
+	sync::mpsc,
+	thread,
 };
 
 use eyre::{bail, eyre, Context, Result};
-use nix::sys::stat::{umask, Mode};
-use tempfile::TempDir;
+use indicatif::{ProgressBar, ProgressStyle};
+use nix::errno::Errno;
 
-use super::Builder;
+use super::{escape_grub_string, Builder};
+use crate::config::GenerationSort;
 
 impl Builder<'_> {
 	pub fn entries(&mut self) -> Result<&mut Self> {
 		self.append_default_entries()?;
 		self.append_profiles()?;
+		self.append_custom_cfg()?;
+		self.resolve_default_entry()?;
 
 		Ok(self)
 	}
 
+	/// Points `set default` at the entry whose title `default` is, now that
+	/// it's known which submenu, if any, it ended up in.
+	fn resolve_default_entry(&mut self) -> Result<()> {
+		let default = self.config.default_entry;
+		// Indices, e.g. `1>0` for the first entry of the second submenu, are
+		// understood by GRUB as is.
+		let is_index_path = default
+			.split('>')
+			.all(|i| !i.is_empty() && i.chars().all(|c| c.is_ascii_digit()));
+		if self.config.save_default() || is_index_path {
+			return Ok(());
+		}
+
+		// As GRUB refers to entries in submenus, with any `>` in titles doubled.
+		let path = |titles: &[String]| {
+			titles
+				.iter()
+				.map(|title| title.replace('>', ">>"))
+				.collect::<Vec<_>>()
+				.join(">")
+		};
+
+		// Either the entry's full path, e.g. `Submenu>Entry`, or just its title.
+		let matched = self
+			.menu_entries
+			.iter()
+			.find(|titles| path(titles) == default)
+			.or_else(|| {
+				self.menu_entries
+					.iter()
+					.find(|titles| titles.last().is_some_and(|t| t == default))
+			});
+
+		let Some(matched) = matched else {
+			// Entries from `extraEntries` aren't tracked, so look for them as written.
+			let extra = self.config.extra_entries;
+			let title = escape_grub_string(default);
+			if extra.contains(&format!("menuentry \"{title}\""))
+				|| extra.contains(&format!("menuentry '{default}'"))
+			{
+				return Ok(());
+			}
+			// GRUB matches ids given with `--id` as well, whether of our entries or
+			// ones in `extraEntries`.
+			let id = default.rsplit('>').next().unwrap_or(default);
+			if entry_ids(&self.inner).any(|i| i == id) {
+				return Ok(());
+			}
+			// It may still be added by os-prober or custom.cfg, which can't be
+			// known until GRUB reads them.
+			eprintln!(
+				"warning: no menu entry is titled `{default}`, as given by `default`, unless \
+				 os-prober or custom.cfg adds one"
+			);
+			return Ok(());
+		};
+
+		// GRUB reads `>` followed by an escaped `>` as an escaped `>` followed by
+		// the separator instead, so such a path would point elsewhere.
+		if matched.iter().skip(1).any(|title| title.starts_with('>')) {
+			eprintln!(
+				"warning: `{default}`, as given by `default`, is in a submenu whose title starts \
+				 with `>`, which GRUB can't refer to by title - use its index instead, e.g. \
+				 `1>2>0`"
+			);
+			return Ok(());
+		}
+
+		let matched = path(matched);
+		self.inner = self.inner.replacen(
+			&format!("set default=\"{}\"", escape_grub_string(default)),
+			&format!("set default=\"{}\"", escape_grub_string(&matched)),
+			1,
+		);
+		Ok(())
+	}
+
+	/// Starts a submenu, which entries are added to until it's closed.
+	fn open_submenu(&mut self, title: &str) -> Result<()> {
+		writeln!(
+			&mut self.inner,
+			r#"submenu "{}" --class submenu {{"#,
+			escape_grub_string(title)
+		)?;
+		self.submenus.push(title.to_owned());
+		Ok(())
+	}
+
+	fn close_submenu(&mut self) -> Result<()> {
+		writeln!(&mut self.inner, "}}")?;
+		self.submenus.pop();
+		Ok(())
+	}
+
 	fn append_default_entries(&mut self) -> Result<()> {
-		// extraEntries could refer to @bootRoot@, which we have to substitute
-		let extra_entries = self
-			.config
-			.extra_entries
-			.replace("@bootRoot@", &self.grub_boot.path.to_string_lossy());
+		let extra_entries = self.substitute(self.config.extra_entries);
 
 		if self.config.extra_entries_before_nixos {
 			writeln!(&mut self.inner, "{extra_entries}")?;
 		}
 
 		self.add_generation(
-			env!("DISTRO_NAME"),
+			self.config.distro_name,
 			"",
 			self.default_config,
 			self.config.entry_options,
 			true,
 		)?;
+		self.append_quirk_entries()?;
 
 		if !self.config.extra_entries_before_nixos {
 			writeln!(&mut self.inner, "{extra_entries}")?;
@@ -47,79 +143,203 @@ impl Builder<'_> {
 		Ok(())
 	}
 
+	fn append_quirk_entries(&mut self) -> Result<()> {
+		if self.config.quirk_entries.is_empty() {
+			return Ok(());
+		}
+
+		self.open_submenu(&format!("{} - Troubleshooting", self.config.distro_name))?;
+
+		let options = self.with_class(self.config.entry_options, true);
+		for quirk in self.config.quirk_entries.clone() {
+			self.add_entry(
+				&format!("{} - {}", self.config.distro_name, quirk.title),
+				self.default_config,
+				&options,
+				quirk.params,
+				true,
+			)?;
+		}
+
+		self.close_submenu()?;
+
+		Ok(())
+	}
+
 	fn append_profiles(&mut self) -> Result<()> {
+		let profiles_dir = self.config.profiles_dir();
+
 		self.add_profile(
-			Path::new("/nix/var/nix/profiles/system"),
-			&format!("{} - All configurations", env!("DISTRO_NAME")),
+			&profiles_dir.join("system"),
+			&format!("{} - All configurations", self.config.distro_name),
 		)?;
 
-		if let Ok(system_profiles) = fs::read_dir("/nix/var/nix/profiles/system-profiles") {
-			for profile in system_profiles {
-				let profile = profile?;
-				let file_name = profile.file_name();
-				let Some(name) = file_name.to_str() else {
-					continue;
+		for (name, profile) in self.system_profiles() {
+			let len = self.inner.len();
+			let submenus = self.submenus.len();
+			let menu_entries = self.menu_entries.len();
+			if let Err(e) = self.add_profile(
+				&profile,
+				&format!("{} - Profile '{name}'", self.config.distro_name),
+			) {
+				// Drop whatever was already written for it.
+				self.inner.truncate(len);
+				self.submenus.truncate(submenus);
+				self.menu_entries.truncate(menu_entries);
+				eprintln!("warning: skipping system profile '{name}': {e:#}");
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Lists every generation of every profile, and why it's left off the
+	/// menu if it is, without writing anything.
+	pub fn list_generations(&self) -> Result<String> {
+		let mut out = String::new();
+		let mut profiles = vec![(
+			String::from("system"),
+			self.config.profiles_dir().join("system"),
+		)];
+		profiles.extend(self.system_profiles());
+
+		for (name, profile) in profiles {
+			writeln!(out, "{name}:")?;
+
+			let shown = self.menu_generations(&profile)?;
+			let mut links = generation_links(&profile)?;
+			links.sort_by_key(|&(_, gen)| Reverse(gen));
+
+			for (link, gen) in links {
+				let date = Self::generation_date_from_link(&link)?;
+				let version = self.read_generation_file(&link.join("nixos-version"));
+
+				let skipped = if self.config.exclude_generations.contains(&gen) {
+					Some(String::from("excluded by `excludeGenerations`"))
+				} else if !shown.iter().any(|(_, g)| *g == gen) {
+					Some(String::from("beyond the configuration limit"))
+				} else if let Err(e) = &version {
+					Some(format!("cannot read nixos-version: {e}"))
+				} else {
+					missing_boot_file(&link).map(|file| format!("missing {file}"))
 				};
 
-				if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
-					self.add_profile(&profile.path(), &format!("@distroName@ - Profile '{name}'"))?;
+				let version = version.as_deref().map_or("unknown version", str::trim_end);
+				match skipped {
+					Some(reason) => writeln!(out, "  {gen}\t{date}\t{version}\tskipped: {reason}")?,
+					None => writeln!(out, "  {gen}\t{date}\t{version}")?,
 				}
 			}
+		}
+
+		Ok(out)
+	}
+
+	/// The profiles in `<profilesDir>/system-profiles`, by name.
+	fn system_profiles(&self) -> Vec<(String, PathBuf)> {
+		let Ok(system_profiles) = fs::read_dir(self.config.profiles_dir().join("system-profiles"))
+		else {
+			return vec![];
 		};
 
-		Ok(())
+		let mut profiles = vec![];
+		for profile in system_profiles {
+			// One bad entry shouldn't keep every other profile off the menu.
+			let profile = match profile {
+				Ok(profile) => profile,
+				Err(e) => {
+					eprintln!("warning: skipping unreadable system profile entry: {e}");
+					continue;
+				}
+			};
+			let file_name = profile.file_name();
+			let Some(name) = file_name.to_str() else {
+				continue;
+			};
+
+			// Anything else is most likely not a profile, but e.g. a generation
+			// link like `foo-1-link`.
+			if name
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+				&& !name.ends_with("-link")
+			{
+				profiles.push((name.to_owned(), profile.path()));
+			}
+		}
+		profiles
 	}
 
-	// Helpers
-	fn add_profile(&mut self, profile: &Path, description: &str) -> Result<()> {
+	fn append_custom_cfg(&mut self) -> Result<()> {
+		if !self.config.allow_custom_cfg {
+			return Ok(());
+		}
+
+		// Same snippet as GRUB's own `41_custom`, so users can add entries by hand
+		// without them being overwritten on the next rebuild.
 		writeln!(
 			&mut self.inner,
-			r#"submenu "{description}" --class submenu {{"#
+			r#"if [ -f ${{config_directory}}/custom.cfg ]; then
+  source ${{config_directory}}/custom.cfg
+elif [ -z "${{config_directory}}" -a -f $prefix/custom.cfg ]; then
+  source $prefix/custom.cfg
+fi"#
 		)?;
 
-		let Some(parent) = profile.parent() else {
-			bail!("Profile directory should not be root!")
-		};
-		let Some(name) = profile.file_name() else {
-			bail!(
-				"Profile `{}` somehow does not have a file name!",
-				profile.display()
-			)
-		};
-
-		let mut links = fs::read_dir(parent)?
-			.filter_map(|m| {
-				let m = m.ok()?;
-				let filename = m.file_name();
-				let file = filename.to_string_lossy();
-
-				// Extract the generation from the file name
-				// The file name would look something like "system-263-link",
-				// here `system` is the profile name.
-				let Some((gen, "link")) = file.rsplit_once('-') else {
-					return None;
-				};
-				let (profile, gen) = gen.rsplit_once('-')?;
+		Ok(())
+	}
 
-				if profile == name {
-					Some((m.path(), gen.parse::<u32>().ok()?))
-				} else {
-					None
-				}
-			})
-			.collect::<Vec<_>>();
+	// Helpers
+	fn add_profile(&mut self, profile: &Path, description: &str) -> Result<()> {
+		self.open_submenu(description)?;
+
+		let mut generations = self.menu_generations(profile)?;
+		if self.config.group_generations_by_month {
+			// Each month's generations have to be next to each other to share a
+			// submenu, which e.g. sorting by kernel version doesn't keep them. This
+			// sort is stable, so within a month they stay in the configured order.
+			generations.sort_by_cached_key(|(link, _)| {
+				Reverse(
+					Self::generation_date_from_link(link)
+						.ok()
+						.map(|date| (date.year(), u8::from(date.month()))),
+				)
+			});
+		}
 
-		links.sort_by_key(|&(_, gen)| Reverse(gen));
+		let mut current_month = None;
 
-		for (link, gen) in links.into_iter().take(self.config.configuration_limit) {
-			let Ok(version) = std::fs::read_to_string(link.join("nixos-version")) else {
-				eprintln!("skipping corrupt system profile entry '{}'", link.display());
-				continue;
+		for (link, gen) in generations {
+			let version = match self.read_generation_file(&link.join("nixos-version")) {
+				Ok(version) => version,
+				Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+					eprintln!(
+						"warning: skipping system profile entry '{}': {e}",
+						link.display()
+					);
+					continue;
+				}
+				Err(_) => {
+					eprintln!("skipping corrupt system profile entry '{}'", link.display());
+					continue;
+				}
 			};
 			let date = Self::generation_date_from_link(&link)?;
 
+			if self.config.group_generations_by_month {
+				let month = format!("{}-{:02}", date.year(), u8::from(date.month()));
+
+				if current_month.as_ref() != Some(&month) {
+					if current_month.is_some() {
+						self.close_submenu()?;
+					}
+					self.open_submenu(&month)?;
+					current_month = Some(month);
+				}
+			}
+
 			self.add_generation(
-				&format!("@distroName@ - Configuration {gen}"),
+				&format!("{} - Configuration {gen}", self.config.distro_name),
 				&format!(" ({date} - {version})"),
 				&link,
 				self.config.sub_entry_options,
@@ -127,9 +347,60 @@ impl Builder<'_> {
 			)?;
 		}
 
+		if current_month.is_some() {
+			self.close_submenu()?;
+		}
+		self.close_submenu()?;
+
 		Ok(())
 	}
 
+	/// The generations of a profile that go on the menu, in the order they're
+	/// listed in.
+	fn menu_generations(&self, profile: &Path) -> Result<Vec<(PathBuf, u32)>> {
+		let Some(name) = profile.file_name() else {
+			bail!(
+				"Profile `{}` somehow does not have a file name!",
+				profile.display()
+			)
+		};
+
+		// Excluded before the limit, so they don't take up any of its slots.
+		let mut links = generation_links(profile)?;
+		links.retain(|(_, gen)| !self.config.exclude_generations.contains(gen));
+
+		// The limit always keeps the newest generations; the sort order only
+		// decides how those are presented.
+		links.sort_by_key(|&(_, gen)| Reverse(gen));
+		let limit = name
+			.to_str()
+			.and_then(|name| self.config.profile_configuration_limits.get(name))
+			.copied()
+			.unwrap_or(self.config.configuration_limit);
+		links.truncate(limit);
+
+		match self.config.generation_sort {
+			GenerationSort::Generation => {}
+			GenerationSort::KernelVersion => {
+				// Unparseable versions compare as `None`, which sorts them last.
+				links.sort_by_cached_key(|(link, _)| {
+					Reverse(
+						Self::kernel_version_from_link(link)
+							.ok()
+							.and_then(|v| parse_kernel_version(&v)),
+					)
+				});
+			}
+			GenerationSort::Date => {
+				links.sort_by_cached_key(|(link, _)| {
+					Reverse(link.symlink_metadata().and_then(|m| m.modified()).ok())
+				});
+			}
+		}
+
+		Ok(links)
+	}
+
 	fn add_generation(
 		&mut self,
 		name: &str,
@@ -142,45 +413,53 @@ impl Builder<'_> {
 			.map(|d| d.map(|p| p.path()))
 			.collect::<Result<Vec<_>, _>>()?;
 		links.sort();
+		// A limit of 0 means there's none, as does leaving it unset.
+		if let Some(limit) = self.config.specialisation_limit.filter(|&l| l > 0) {
+			links.truncate(limit);
+		}
 
 		if !current && !links.is_empty() {
-			writeln!(
-				&mut self.inner,
-				r#"submenu "> {name}{name_suffix}" --class submenu {{"#
-			)?;
+			self.open_submenu(&format!("> {name}{name_suffix}"))?;
 		}
 
+		let specialisations_title = format!("> {name}{name_suffix} - Specialisations");
+
 		let mut name = name.to_owned();
 		if !links.is_empty() {
 			name.push_str(" - Default");
 		}
 		name.push_str(name_suffix);
 
-		self.add_entry(&name, path, options, current)?;
+		let options = self.with_class(options, current);
+		self.add_entry(&name, path, &options, "", current)?;
+
+		// The default entry of the current generation has to stay at the top
+		// level so it remains the default, hence only its specialisations go in.
+		let group_current = current && self.config.group_current_specialisations;
+		if group_current && !links.is_empty() {
+			self.open_submenu(&specialisations_title)?;
+		}
 
 		for link in &links {
 			let date = Self::generation_date_from_link(link)?;
 
-			let version = if let Ok(version) = fs::read_to_string(link.join("nixos-version")) {
-				version
-			} else {
-				let modules = link
-					.join("kernel")
-					.canonicalize()?
-					.parent()
-					.ok_or_else(|| {
-						eyre!("Somehow {}/kernel doesn't have a parent..?", link.display())
-					})?
-					.join("lib/modules");
-
-				let Some(version) = fs::read_dir(&modules)?.find_map(|m| {
-					m.ok()
-						.and_then(|p| Some(p.path().file_name()?.to_string_lossy().into_owned()))
-				}) else {
-					bail!("Could not deduce the current NixOS version")
-				};
-
-				version
+			let version = match self.read_generation_file(&link.join("nixos-version")) {
+				Ok(version) => version,
+				Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+					eprintln!("warning: skipping specialisation '{}': {e}", link.display());
+					continue;
+				}
+				Err(_) => match Self::kernel_version_from_link(link) {
+					Ok(version) => version,
+					// Not worth failing the whole rebuild over, it's only for display.
+					Err(e) => {
+						eprintln!(
+							"warning: cannot determine the version of specialisation '{}': {e:#}",
+							link.display()
+						);
+						String::from("unknown version")
+					}
+				},
 			};
 
 			let entry_name =
@@ -191,57 +470,194 @@ impl Builder<'_> {
 					)
 				});
 
-			self.add_entry(&format!("{name} - {entry_name}"), link, "", true)?;
+			let options = self.with_class("", current);
+			self.add_entry(&format!("{name} - {entry_name}"), link, &options, "", true)?;
 		}
 
-		if !current && !links.is_empty() {
-			writeln!(&mut self.inner, "}}")?;
+		if (!current || group_current) && !links.is_empty() {
+			self.close_submenu()?;
 		}
 
 		Ok(())
 	}
 
+	/// Reads a file from a generation, giving up after the configured timeout
+	/// so a single generation on slow storage can't hang the whole build.
+	fn read_generation_file(&self, path: &Path) -> io::Result<String> {
+		let (tx, rx) = mpsc::channel();
+		let owned = path.to_owned();
+		// If this times out, the thread is left blocked on the read. That's fine, as
+		// it dies with the process.
+		thread::spawn(move || tx.send(fs::read_to_string(owned)));
+
+		rx.recv_timeout(self.args.read_timeout).unwrap_or_else(|_| {
+			Err(io::Error::new(
+				io::ErrorKind::TimedOut,
+				format!(
+					"reading {} timed out after {}s",
+					path.display(),
+					self.args.read_timeout.as_secs()
+				),
+			))
+		})
+	}
+
+	/// Deduces the version of the kernel from its modules directory.
+	fn kernel_version_from_link(link: &Path) -> Result<String> {
+		let modules = link
+			.join("kernel")
+			.canonicalize()?
+			.parent()
+			.ok_or_else(|| eyre!("Somehow {}/kernel doesn't have a parent..?", link.display()))?
+			.join("lib/modules");
+
+		let Some(version) = fs::read_dir(&modules)?.find_map(|m| {
+			m.ok()
+				.and_then(|p| Some(p.path().file_name()?.to_string_lossy().into_owned()))
+		}) else {
+			bail!("Could not deduce the current NixOS version")
+		};
+
+		Ok(version)
+	}
+
 	fn generation_date_from_link(link: &Path) -> Result<time::Date> {
-		let sys_time = link.metadata()?.modified()?;
+		// The link's own time is when the generation was made, as everything in
+		// the store it points to has the same one.
+		let sys_time = link.symlink_metadata()?.modified()?;
 
 		Ok(time::OffsetDateTime::from(sys_time).date())
 	}
 
-	fn add_entry(&mut self, name: &str, path: &Path, options: &str, current: bool) -> Result<()> {
+	/// Adds the class configured for current or older generations' entries to
+	/// their options, so themes can tell them apart.
+	fn with_class(&self, options: &str, current: bool) -> String {
+		let class = if current {
+			self.config.entry_class
+		} else {
+			self.config.sub_entry_class
+		};
+
+		match class {
+			Some(class) if options.is_empty() => format!("--class {class}"),
+			Some(class) => format!("{options} --class {class}"),
+			None => options.to_owned(),
+		}
+	}
+
+	fn add_entry(
+		&mut self,
+		name: &str,
+		path: &Path,
+		options: &str,
+		extra_params: &str,
+		current: bool,
+	) -> Result<()> {
+		// A broken generation shouldn't just vanish from the menu.
+		if let Some(file) = missing_boot_file(path) {
+			eprintln!(
+				"warning: skipping \"{name}\", as {} has no {file}",
+				path.display()
+			);
+			return Ok(());
+		}
+
 		let kernel_dir = path.join("kernel");
 		let initrd_dir = path.join("initrd");
 
-		if !(kernel_dir.exists() && initrd_dir.exists()) {
-			return Ok(());
+		let xen = path.join("xen.gz");
+
+		// The current generation is always kept, so there's something to boot.
+		let max_kernels = self
+			.args
+			.max_kernels
+			.filter(|_| !path.starts_with(self.default_config));
+		if let Some(max_kernels) = max_kernels {
+			let mut files = vec![kernel_dir.as_path(), initrd_dir.as_path()];
+			if xen.exists() {
+				files.push(&xen);
+			}
+			files.extend(self.config.early_initrds.iter().copied());
+
+			let mut new_copies = self.new_kernel_copies(&files)?;
+			if let Some(secrets_name) = initrd_secrets_name(path)? {
+				if !self
+					.copied
+					.contains(&self.boot_dir.join("kernels").join(secrets_name))
+				{
+					new_copies += 1;
+				}
+			}
+
+			// Entries are added newest first, so this keeps the newest ones.
+			if self.kernel_copies + new_copies > max_kernels {
+				eprintln!(
+					"warning: skipping \"{name}\", as copying its kernel would exceed the limit \
+					 of {max_kernels} kernels"
+				);
+				return Ok(());
+			}
 		}
 
 		let kernel_dir = self.copy_to_kernels_dir(&kernel_dir)?;
 		let initrd_dir = self.copy_to_kernels_dir(&initrd_dir)?;
 
+		// These have to be loaded before the main initrd, in order, as that's how
+		// the kernel expects e.g. microcode updates to be concatenated.
+		let mut early_initrds = vec![];
+		for initrd in self.config.early_initrds.clone() {
+			early_initrds.push(self.copy_to_kernels_dir(initrd)?);
+		}
+
 		// Include second initrd with secrets
-		let secrets_dir = self
-			.append_initrd_secrets(name, path, current)?
-			.unwrap_or_default();
+		let secrets_dir = self.append_initrd_secrets(name, path, current)?;
+		// The kernels directory needs its own search if it's another filesystem.
+		let kernels_search = self.grub_secrets.as_ref().map(|k| k.search.clone());
+		let secrets_dir = secrets_dir.unwrap_or_default();
 
 		// FIXME: $confName
 
-		let kernel_params = format!(
+		let mut kernel_params = format!(
 			"init={} {}",
 			path.join("init").canonicalize()?.display(),
-			fs::read_to_string(path.join("kernel-params"))?
+			// These usually end with a newline, which would break the entry.
+			fs::read_to_string(path.join("kernel-params"))?.trim_end()
 		);
+		if !extra_params.is_empty() {
+			kernel_params.push(' ');
+			kernel_params.push_str(extra_params);
+		}
+		if self.config.dedupe_kernel_params {
+			kernel_params = dedupe_kernel_params(&kernel_params);
+		}
 
-		let xen = path.join("xen.gz");
 		let xen = if xen.exists() {
-			Some((
-				self.copy_to_kernels_dir(&xen)?,
-				fs::read_to_string(path.join("xen-params")).unwrap_or_default(),
-			))
+			// Goes through the kernels directory like the kernel itself does, so it
+			// is kept around by `remove_old_kernels` too.
+			let xen = self.copy_to_kernels_dir(&xen)?;
+			let xen_params = match fs::read_to_string(path.join("xen-params")) {
+				Ok(params) => params.trim_end().to_owned(),
+				Err(e) => {
+					eprintln!(
+						"warning: \"{name}\" has a Xen hypervisor but its parameters can't be \
+						 read ({e}), booting it without any"
+					);
+					String::new()
+				}
+			};
+			Some((xen, xen_params))
 		} else {
 			None
 		};
 
-		writeln!(&mut self.inner, r#"menuentry "{name}" {options} {{"#)?;
+		writeln!(
+			&mut self.inner,
+			r#"menuentry "{}" {options} {{"#,
+			escape_grub_string(name)
+		)?;
+		let mut titles = self.submenus.clone();
+		titles.push(name.to_owned());
+		self.menu_entries.push(titles);
 		if self.config.save_default() {
 			writeln!(&mut self.inner, "  savedefault")?;
 		}
@@ -249,32 +665,49 @@ impl Builder<'_> {
 		if let Some(store) = &self.grub_store {
 			writeln!(&mut self.inner, "{}", store.search)?;
 		}
-		if let Some(conf) = &self.config.extra_per_entry_config {
+		if let Some(search) = kernels_search {
+			writeln!(&mut self.inner, "{search}")?;
+		}
+		if let Some(conf) = self.config.extra_per_entry_config {
+			let conf = self.substitute(conf);
 			writeln!(&mut self.inner, "  {conf}")?;
 		}
 		if let Some((xen, xen_params)) = xen {
 			writeln!(
 				&mut self.inner,
-				"
-  multiboot {xen} {xen_params}
-  module {kernel} {kernel_params}
-  module {initrd} {secrets}
-                ",
-				xen = xen.display(),
-				kernel = kernel_dir.display(),
-				initrd = initrd_dir.display(),
-				secrets = secrets_dir.display(),
+				"  multiboot {} {xen_params}",
+				xen.display()
+			)?;
+			writeln!(
+				&mut self.inner,
+				"  module {} {kernel_params}",
+				kernel_dir.display()
+			)?;
+			for initrd in &early_initrds {
+				writeln!(&mut self.inner, "  module {}", initrd.display())?;
+			}
+			writeln!(
+				&mut self.inner,
+				"  module {} {}",
+				initrd_dir.display(),
+				secrets_dir.display()
 			)?;
 		} else {
+			let early_initrds = early_initrds
+				.iter()
+				.map(|i| format!("{} ", i.display()))
+				.collect::<String>();
+
+			writeln!(
+				&mut self.inner,
+				"  linux {} {kernel_params}",
+				kernel_dir.display()
+			)?;
 			writeln!(
 				&mut self.inner,
-				"
-  linux {kernel} {kernel_params}
-  initrd {initrd} {secrets}
-                ",
-				kernel = kernel_dir.display(),
-				initrd = initrd_dir.display(),
-				secrets = secrets_dir.display(),
+				"  initrd {early_initrds}{} {}",
+				initrd_dir.display(),
+				secrets_dir.display()
 			)?;
 		}
 		writeln!(&mut self.inner, "}}\n")?;
@@ -288,38 +721,29 @@ impl Builder<'_> {
 		path: &Path,
 		current: bool,
 	) -> Result<Option<PathBuf>> {
-		let append_initrd_secrets = path.join("append-initrd-secrets");
-		let Ok(metadata) = fs::metadata(&append_initrd_secrets) else {
+		let Some(secrets_name) = initrd_secrets_name(path)? else {
 			return Ok(None);
 		};
+		let append_initrd_secrets = path.join("append-initrd-secrets");
 
-		// Check if it's an executable file
-		if !(metadata.is_file() && metadata.permissions().mode() & 0b111 != 0) {
-			return Ok(None);
-		}
-
-		let canonicalized = path.canonicalize()?;
-		let Some(system_name) = canonicalized.file_name().and_then(|s| s.to_str()) else {
-			bail!(
-				"Entry path {} somehow doesn't have a file name?",
-				path.display()
-			)
-		};
-
-		let kernels = self.config.boot_path.join("kernels");
-		let secrets_name = format!("{system_name}-secrets");
+		let kernels = self.boot_dir.join("kernels");
 		let initrd_secrets_path = kernels.join(&secrets_name);
 
 		let secrets_added = self.dry_run || {
 			fs::create_dir_all(&kernels)?;
 			fs::set_permissions(&kernels, PermissionsExt::from_mode(0o755))?;
 
-			// Make sure initrd is not world readable (won't work if /boot is FAT)
-			let old_umask = umask(Mode::from_bits_truncate(0o137));
-
-			let initrd_secrets_path_temp = TempDir::with_prefix(&secrets_name)?;
-
-			let status = Command::new(&append_initrd_secrets)
+			// The script appends a cpio archive of the secrets to the file it's
+			// given, as it would to an initrd. It's created next to where it ends up,
+			// so it can be renamed into place without crossing filesystems, and not
+			// world readable (which won't work if /boot is FAT).
+			let initrd_secrets_path_temp = tempfile::Builder::new()
+				.prefix(&format!("{secrets_name}.tmp"))
+				.permissions(fs::Permissions::from_mode(0o640))
+				.tempfile_in(&kernels)?;
+
+			let status = self
+				.command(&append_initrd_secrets)
 				.arg(initrd_secrets_path_temp.path())
 				.status()?;
 
@@ -338,48 +762,56 @@ impl Builder<'_> {
 				}
 			}
 
-			// Restore umask
-			// Temp dir is automatically cleaned up.
-			umask(old_umask);
-
 			// Check whether any secrets were actually added
-			if fs::metadata(&initrd_secrets_path_temp).map_or(0, |m| m.len()) > 0 {
-				fs::rename(&initrd_secrets_path_temp, &initrd_secrets_path)
-					.context("Failed to move initrd secrets into place")?;
-
-				self.copied.insert(initrd_secrets_path);
+			// Temp file is automatically cleaned up otherwise.
+			let secrets = fs::read(initrd_secrets_path_temp.path())?;
+			if secrets.is_empty() {
+				false
+			} else {
+				// Which secrets the script copies can't be told from the outside, so
+				// it's always run, but an unchanged file is left alone.
+				if fs::read(&initrd_secrets_path).ok().as_ref() != Some(&secrets) {
+					initrd_secrets_path_temp
+						.persist(&initrd_secrets_path)
+						.context("Failed to move initrd secrets into place")?;
+				}
 
 				true
-			} else {
-				false
 			}
 		};
 
-		Ok(if secrets_added {
-			let mut secrets_dir = self.grub_boot.path.join("kernels");
-			secrets_dir.push(&secrets_name);
-			Some(secrets_dir)
-		} else {
-			None
-		})
+		if secrets_added {
+			// Counts towards `--max-kernels` just like a copied initrd.
+			if self.copied.insert(initrd_secrets_path) {
+				self.kernel_copies += 1;
+			}
+		}
+
+		// Has to resolve to `initrd_secrets_path`, just as seen by GRUB.
+		Ok(secrets_added.then(|| self.grub_kernels_path(&secrets_name)))
 	}
 
 	fn copy_to_kernels_dir(&mut self, path: &Path) -> Result<PathBuf> {
-		let path = path.canonicalize()?;
-
-		let Ok(path) = path.strip_prefix("/nix/store") else {
-			bail!("Path {} is not in /nix/store!", path.display())
-		};
+		let src = path.canonicalize()?;
 
 		// GRUB store exists, which means the kernels and initrds are on the same
 		// filesystem as / and /nix/store. No need to copy!
-		if let Some(store) = &self.grub_store {
+		let store_path = self.config.store_path;
+		if let (Some(store), Ok(path)) = (&self.grub_store, src.strip_prefix(store_path)) {
 			return Ok(store.path.join(path));
 		}
 
-		let name = path.to_string_lossy().replace('/', "-");
-		let mut dst = self.config.boot_path.join("kernels");
-		dst.push(&name);
+		if !src.starts_with(store_path) {
+			// Some generations have regular files in place of the usual store
+			// symlinks. Those can live anywhere, so always copy them.
+			eprintln!(
+				"warning: {} is not in {}, copying it to the kernels directory",
+				src.display(),
+				store_path.display()
+			);
+		}
+		let dst = self.kernels_dir_dst(&src);
+		let path = src.as_path();
 
 		// Don't copy the file if $dst already exists.  This means that we
 		// have to create $dst atomically to prevent partially copied
@@ -393,15 +825,482 @@ impl Builder<'_> {
 			};
 			name.push(".tmp");
 			let tmp = dst.with_file_name(name);
+			// A fresh /boot doesn't have the kernels directory yet.
+			fs::create_dir_all(self.boot_dir.join("kernels"))?;
 
-			fs::copy(path, &tmp)
-				.with_context(|| format!("Cannot copy {} to {}", path.display(), tmp.display()))?;
+			if !(self.config.prefer_hardlinks && self.hard_link(path, &tmp)?) {
+				let copied = if self.args.progress && io::stderr().is_terminal() {
+					copy_with_progress(path, &tmp)
+				} else {
+					fs::copy(path, &tmp).map(|_| ())
+				};
+				copied.with_context(|| {
+					format!("Cannot copy {} to {}", path.display(), tmp.display())
+				})?;
+			}
 			fs::rename(&tmp, &dst).with_context(|| {
 				format!("Cannot rename {} to {}", path.display(), tmp.display())
 			})?;
 		}
 
-		self.copied.insert(dst);
-		Ok(self.grub_boot.path.join("kernels/name"))
+		let Some(name) = dst.file_name() else {
+			bail!("Somehow path {} does not have a file name", dst.display())
+		};
+		let grub_path = self.grub_kernels_path(name);
+
+		if self.copied.insert(dst) {
+			self.kernel_copies += 1;
+		}
+		Ok(grub_path)
+	}
+
+	/// The path GRUB reaches a file in `<bootPath>/kernels` by.
+	fn grub_kernels_path(&self, name: impl AsRef<Path>) -> PathBuf {
+		match &self.grub_secrets {
+			Some(kernels) => kernels.path.join(name),
+			None => self.grub_boot.path.join("kernels").join(name),
+		}
+	}
+
+	/// Tries to hard link instead of copying, to save space when /boot is on
+	/// the same filesystem as the store after all. Returns whether it
+	/// succeeded.
+	fn hard_link(&self, src: &Path, dst: &Path) -> Result<bool> {
+		// Unlike copying, linking doesn't overwrite leftovers of an interrupted run.
+		if let Err(e) = fs::remove_file(dst) {
+			if e.kind() != io::ErrorKind::NotFound {
+				return Err(e).with_context(|| format!("Cannot remove {}", dst.display()));
+			}
+		}
+
+		match fs::hard_link(src, dst) {
+			Ok(()) => Ok(true),
+			// Either a different filesystem, or protected_hardlinks forbidding it.
+			Err(e)
+				if e.raw_os_error() == Some(Errno::EXDEV as i32)
+					|| e.kind() == io::ErrorKind::PermissionDenied =>
+			{
+				Ok(false)
+			}
+			Err(e) => Err(e).with_context(|| {
+				format!("Cannot hard link {} to {}", src.display(), dst.display())
+			}),
+		}
+	}
+
+	/// Counts how many of the given files aren't in the kernels directory yet.
+	fn new_kernel_copies(&self, files: &[&Path]) -> Result<usize> {
+		// Nothing gets copied when the store is directly accessible.
+		if self.grub_store.is_some() {
+			return Ok(0);
+		}
+
+		let mut count = 0;
+		for file in files {
+			if !self
+				.copied
+				.contains(&self.kernels_dir_dst(&file.canonicalize()?))
+			{
+				count += 1;
+			}
+		}
+		Ok(count)
+	}
+
+	/// Where a kernel or initrd (given by its canonical path) gets copied to.
+	fn kernels_dir_dst(&self, src: &Path) -> PathBuf {
+		match src.strip_prefix(self.config.store_path) {
+			Ok(path) => self.kernels_dir_path(path),
+			Err(_) => {
+				let name = Path::new("non-store").join(src.strip_prefix("/").unwrap_or(src));
+				self.kernels_dir_path(&name)
+			}
+		}
+	}
+
+	/// Where a file in the store is placed when copied into the kernels
+	/// directory.
+	fn kernels_dir_path(&self, store_path: &Path) -> PathBuf {
+		let name = store_path.to_string_lossy().replace('/', "-");
+		let mut dst = self.boot_dir.join("kernels");
+		dst.push(name);
+		dst
+	}
+}
+
+/// All of a profile's generations, found by their links next to it, e.g.
+/// `system-263-link` for the `system` profile.
+fn generation_links(profile: &Path) -> Result<Vec<(PathBuf, u32)>> {
+	let Some(parent) = profile.parent() else {
+		bail!("Profile directory should not be root!")
+	};
+	let Some(name) = profile.file_name() else {
+		bail!(
+			"Profile `{}` somehow does not have a file name!",
+			profile.display()
+		)
+	};
+
+	Ok(fs::read_dir(parent)?
+		.filter_map(|m| {
+			let m = m.ok()?;
+			let filename = m.file_name();
+			let file = filename.to_string_lossy();
+
+			// Extract the generation from the file name
+			// The file name would look something like "system-263-link",
+			// here `system` is the profile name.
+			let Some((gen, "link")) = file.rsplit_once('-') else {
+				return None;
+			};
+			let (profile, gen) = gen.rsplit_once('-')?;
+
+			if profile == name {
+				Some((m.path(), gen.parse::<u32>().ok()?))
+			} else {
+				None
+			}
+		})
+		.collect())
+}
+
+/// The ids entries were given with `--id`, as GRUB reads them.
+fn entry_ids(cfg: &str) -> impl Iterator<Item = &str> {
+	cfg.split("--id").skip(1).filter_map(|rest| {
+		let rest = rest.strip_prefix(['=', ' '])?;
+		let id = rest.split_whitespace().next()?;
+		Some(id.trim_matches(['\'', '"']))
+	})
+}
+
+/// The name a generation's initrd secrets are given in the kernels
+/// directory, if it has an `append-initrd-secrets` script to create them.
+fn initrd_secrets_name(path: &Path) -> Result<Option<String>> {
+	let Ok(metadata) = fs::metadata(path.join("append-initrd-secrets")) else {
+		return Ok(None);
+	};
+
+	// Check if it's an executable file
+	if !(metadata.is_file() && metadata.permissions().mode() & 0o111 != 0) {
+		return Ok(None);
+	}
+
+	let canonicalized = path.canonicalize()?;
+	let Some(system_name) = canonicalized.file_name().and_then(|s| s.to_str()) else {
+		bail!(
+			"Entry path {} somehow doesn't have a file name?",
+			path.display()
+		)
+	};
+
+	Ok(Some(format!("{system_name}-secrets")))
+}
+
+/// Which of the files needed to boot a generation it lacks, if any.
+fn missing_boot_file(path: &Path) -> Option<&'static str> {
+	["kernel", "initrd"]
+		.into_iter()
+		.find(|file| !path.join(file).exists())
+}
+
+/// Copies a file like [`fs::copy`], while showing a progress bar, as initrds
+/// can take a while to copy to slow media.
+fn copy_with_progress(src: &Path, dst: &Path) -> io::Result<()> {
+	let mut reader = fs::File::open(src)?;
+	let metadata = reader.metadata()?;
+	let mut writer = io::BufWriter::new(fs::File::create(dst)?);
+
+	let progress = ProgressBar::new(metadata.len())
+		.with_style(
+			ProgressStyle::with_template("{msg} {wide_bar} {bytes}/{total_bytes}")
+				.unwrap_or_else(|_| ProgressStyle::default_bar()),
+		)
+		.with_message(format!("copying {}", src.display()));
+
+	let mut buf = vec![0; 1 << 16];
+	loop {
+		let n = reader.read(&mut buf)?;
+		if n == 0 {
+			break;
+		}
+		writer.write_all(&buf[..n])?;
+		progress.inc(n as u64);
+	}
+	progress.finish_and_clear();
+
+	let file = writer
+		.into_inner()
+		.map_err(io::IntoInnerError::into_error)?;
+	file.set_permissions(metadata.permissions())
+}
+
+/// Normalizes whitespace in kernel parameters and drops all but the last of
+/// any parameters with the same key, e.g. `loglevel=`, as that's the one that
+/// would otherwise win anyway for most parameters. Quoted values, like
+/// `foo="a b"`, are kept whole, as the kernel does.
+fn dedupe_kernel_params(params: &str) -> String {
+	/// Parameters that are meant to be given several times, each adding to
+	/// the others, e.g. a `console=` for each console to log to.
+	const REPEATABLE: &[&str] = &["console", "hugepages", "hugepagesz", "memmap"];
+
+	fn key(param: &str) -> String {
+		let key = param.split_once('=').map_or(param, |(key, _)| key);
+		key.replace('"', "")
+	}
+
+	let mut split = vec![];
+	let mut param = String::new();
+	let mut quoted = false;
+	for c in params.chars() {
+		if c.is_whitespace() && !quoted {
+			if !param.is_empty() {
+				split.push(std::mem::take(&mut param));
+			}
+			continue;
+		}
+		if c == '"' {
+			quoted = !quoted;
+		}
+		param.push(c);
+	}
+	if !param.is_empty() {
+		split.push(param);
+	}
+
+	split
+		.iter()
+		.enumerate()
+		.filter(|&(i, param)| {
+			let param_key = key(param);
+			REPEATABLE.contains(&param_key.as_str())
+				|| !split[i + 1..].iter().any(|p| key(p) == param_key)
+		})
+		.map(|(_, param)| param.as_str())
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+/// Parses the leading numeric components of a kernel version, e.g. `6.1.23` out
+/// of `6.1.23-rt8`.
+fn parse_kernel_version(version: &str) -> Option<Vec<u64>> {
+	let numeric = version
+		.split(|c: char| !c.is_ascii_digit() && c != '.')
+		.next()?;
+
+	let components = numeric
+		.split('.')
+		.map(|c| c.parse().ok())
+		.collect::<Option<Vec<u64>>>()?;
+
+	(!components.is_empty()).then_some(components)
+}
+
+#[cfg(test)]
+mod tests {
+	use roxmltree::Document;
+
+	use super::*;
+	use crate::{
+		builder::testing::{boolean, list, string, Fixture},
+		config::Config,
+	};
+
+	/// Generates grub.cfg with only the default entries, and installs GRUB.
+	fn install(fx: &Fixture) -> Result<()> {
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		let mut builder = fx.builder(config);
+		builder.append_default_entries()?;
+		builder.install()?;
+		Ok(())
+	}
+
+	#[test]
+	fn escapes_grub_strings() {
+		assert_eq!(escape_grub_string("NixOS"), "NixOS");
+		assert_eq!(escape_grub_string(r#"a "b" $c \d"#), r#"a \"b\" \$c \\d"#);
+	}
+
+	#[test]
+	fn kernel_versions() {
+		assert_eq!(parse_kernel_version("6.1.23"), Some(vec![6, 1, 23]));
+		assert_eq!(parse_kernel_version("6.1.23-rt8"), Some(vec![6, 1, 23]));
+		assert_eq!(parse_kernel_version("6.10"), Some(vec![6, 10]));
+		assert!(parse_kernel_version("6.10") > parse_kernel_version("6.9.12"));
+		assert_eq!(parse_kernel_version("rt"), None);
+	}
+
+	#[test]
+	fn dedupe_keeps_the_last_of_each_key() {
+		assert_eq!(
+			dedupe_kernel_params("quiet  loglevel=4 splash loglevel=7 quiet"),
+			"splash loglevel=7 quiet"
+		);
+	}
+
+	#[test]
+	fn dedupe_keeps_quoted_values_whole() {
+		assert_eq!(
+			dedupe_kernel_params(r#"foo="a b" bar foo="c d""#),
+			r#"bar foo="c d""#
+		);
+		assert_eq!(dedupe_kernel_params(r#""foo=a b" foo=c"#), "foo=c");
+	}
+
+	#[test]
+	fn dedupe_keeps_repeatable_params() {
+		assert_eq!(
+			dedupe_kernel_params("console=tty0 console=ttyS0,115200 console=tty0"),
+			"console=tty0 console=ttyS0,115200 console=tty0"
+		);
+	}
+
+	#[test]
+	fn adds_quirk_entries() {
+		let mut fx = Fixture::new();
+		fx.set(
+			"quirkEntries",
+			list(&[String::from(
+				"<attrs><attr name=\"title\"><string value=\"Safe graphics\" /></attr><attr \
+				 name=\"params\"><string value=\"nomodeset\" /></attr></attrs>",
+			)]),
+		);
+
+		install(&fx).unwrap();
+
+		let grub_cfg = fx.grub_cfg();
+		let (_, troubleshooting) = grub_cfg
+			.split_once("submenu \"NixOS - Troubleshooting\"")
+			.unwrap();
+		assert!(troubleshooting.contains("menuentry \"NixOS - Safe graphics\""));
+		assert!(troubleshooting.contains("loglevel=4 nomodeset"));
+	}
+
+	#[test]
+	fn copies_with_progress() {
+		let dir = tempfile::tempdir().unwrap();
+		let src = dir.path().join("initrd");
+		let dst = dir.path().join("initrd-copy");
+		// Spans several reads, the last of them partial.
+		let contents = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+		fs::write(&src, &contents).unwrap();
+		fs::set_permissions(&src, fs::Permissions::from_mode(0o640)).unwrap();
+
+		copy_with_progress(&src, &dst).unwrap();
+
+		assert_eq!(fs::read(&dst).unwrap(), contents);
+		let mode = fs::metadata(&dst).unwrap().permissions().mode();
+		assert_eq!(mode & 0o777, 0o640);
+	}
+
+	#[test]
+	fn escapes_titles() {
+		let mut fx = Fixture::new();
+		fx.set("distroName", string(r#"Nix"OS" $x"#));
+		fx.set(
+			"quirkEntries",
+			list(&[String::from(
+				"<attrs><attr name=\"title\"><string value=\"Safe &quot;graphics&quot;\" \
+				 /></attr><attr name=\"params\"><string value=\"nomodeset\" /></attr></attrs>",
+			)]),
+		);
+
+		install(&fx).unwrap();
+
+		let grub_cfg = fx.grub_cfg();
+		assert!(grub_cfg.contains(r#"menuentry "Nix\"OS\" \$x" --class"#));
+		assert!(grub_cfg.contains(r#"submenu "Nix\"OS\" \$x - Troubleshooting" --class"#));
+		assert!(grub_cfg.contains(r#"menuentry "Nix\"OS\" \$x - Safe \"graphics\"" --class"#));
+	}
+
+	#[test]
+	fn keeps_obsolete_kernels_until_installed() {
+		let mut fx = Fixture::new();
+		fx.set("copyKernels", boolean(true));
+		let obsolete = fx.root().join("boot/kernels/eeee-linux-6.1.0-bzImage");
+		fs::write(&obsolete, "kernel").unwrap();
+
+		fx.fail("grub-install");
+		assert!(install(&fx).is_err());
+		assert!(obsolete.exists());
+
+		fs::remove_file(fx.root().join("fail-grub-install")).unwrap();
+		install(&fx).unwrap();
+		assert!(!obsolete.exists());
+		// Only the current kernel and initrd are left.
+		let kernels = fs::read_dir(fx.root().join("boot/kernels")).unwrap();
+		assert_eq!(kernels.count(), 2);
+	}
+
+	#[test]
+	fn creates_initrd_secrets_in_the_kernels_dir() {
+		let fx = Fixture::new();
+		let system = fs::read_link(fx.root().join("profiles/system-1-link")).unwrap();
+		let script = system.join("append-initrd-secrets");
+		fs::write(
+			&script,
+			format!(
+				"#!/bin/sh\necho \"$1\" > {}\necho secret >> \"$1\"\n",
+				fx.root().join("secrets-arg").display()
+			),
+		)
+		.unwrap();
+		fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+		install(&fx).unwrap();
+
+		// The temporary file is renamed into place, which only works within the
+		// same filesystem.
+		let kernels = fx.root().join("boot/kernels");
+		let temp = fs::read_to_string(fx.root().join("secrets-arg")).unwrap();
+		assert_eq!(Path::new(temp.trim_end()).parent(), Some(&*kernels));
+
+		let secrets = kernels.join("cccc-nixos-system-test-24.05-secrets");
+		assert_eq!(fs::read_to_string(&secrets).unwrap(), "secret\n");
+		assert!(fx
+			.grub_cfg()
+			.contains(" kernels/cccc-nixos-system-test-24.05-secrets\n"));
+	}
+
+	#[test]
+	fn regenerates_initrd_secrets_when_a_secret_changes() {
+		let fx = Fixture::new();
+		let system = fs::read_link(fx.root().join("profiles/system-1-link")).unwrap();
+		let secret = fx.root().join("secret");
+		fs::write(&secret, "hunter2\n").unwrap();
+		// Like NixOS's, which copies its secrets by unquoted paths.
+		let script = system.join("append-initrd-secrets");
+		fs::write(
+			&script,
+			format!("#!/bin/sh\ncat {} >> \"$1\"\n", secret.display()),
+		)
+		.unwrap();
+		fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+		let secrets = fx
+			.root()
+			.join("boot/kernels/cccc-nixos-system-test-24.05-secrets");
+
+		install(&fx).unwrap();
+		assert_eq!(fs::read_to_string(&secrets).unwrap(), "hunter2\n");
+		let mode = fs::metadata(&secrets).unwrap().permissions().mode();
+		assert_eq!(mode & 0o007, 0);
+
+		fs::write(&secret, "correct horse\n").unwrap();
+		install(&fx).unwrap();
+		assert_eq!(fs::read_to_string(&secrets).unwrap(), "correct horse\n");
+	}
+
+	#[test]
+	fn skips_generations_without_an_initrd() {
+		let fx = Fixture::new();
+		let system = fs::read_link(fx.root().join("profiles/system-1-link")).unwrap();
+		fs::remove_file(system.join("initrd")).unwrap();
+
+		assert_eq!(missing_boot_file(&system), Some("initrd"));
+
+		install(&fx).unwrap();
+		assert!(!fx.grub_cfg().contains("menuentry"));
 	}
 }
@@ -1,74 +1,298 @@
 use std::{
-	collections::HashSet,
+	collections::{hash_map::DefaultHasher, HashSet},
+	ffi::OsStr,
+	fmt::Write as _,
 	fs,
-	io::{BufRead, BufReader, BufWriter, Write},
-	os::unix::fs::symlink,
+	hash::{Hash, Hasher},
+	io::{self, BufRead, BufReader, BufWriter, Write},
+	os::unix::fs::{symlink, FileTypeExt, OpenOptionsExt, PermissionsExt},
 	path::{Path, PathBuf},
-	process::Command,
+	process::{Command, Output},
+	thread,
+	time::{Duration, Instant},
 };
 
 use eyre::{bail, Context, Result};
+use rayon::prelude::*;
 
 use super::Builder;
-use crate::config::Config;
+use crate::{
+	args::Args,
+	config::{Config, Password},
+};
 
 impl Builder<'_> {
 	pub fn install(&mut self) -> Result<&mut Self> {
 		let efi_target = EfiTarget::deduce(&self.config)?;
-		let conf = self.config.boot_path.join("grub/grub.cfg");
-		let temp = self.config.boot_path.join("grub/grub.cfg.tmp");
+		let conf = self.grub_cfg_path();
+		let temp = with_suffix(&conf, ".tmp");
 
 		if self.dry_run {
 			println!("{}", self.inner);
 			return Ok(self);
 		}
 
-		fs::write(&temp, &self.inner)?;
+		let plaintext = self
+			.config
+			.users
+			.0
+			.values()
+			.any(|p| matches!(p, Password::Plain(_)));
+		let mode = if plaintext && !self.config.hash_plaintext_passwords {
+			eprintln!(
+				"warning: grub.cfg contains plaintext passwords, so only root can read it - \
+				 consider hashed passwords or `hashPlaintextPasswords` instead"
+			);
+			0o600
+		} else {
+			0o644
+		};
+
+		// The mode only applies to a new file, and has to be set from the start
+		// so the passwords are never readable by anyone else.
+		match fs::remove_file(&temp) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => {
+				return Err(e).with_context(|| format!("Cannot remove {}", temp.display()));
+			}
+			_ => {}
+		}
+		fs::OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.mode(mode)
+			.open(&temp)
+			.and_then(|mut file| file.write_all(self.inner.as_bytes()))
+			.with_context(|| format!("Cannot write {}", temp.display()))?;
 
 		self.append_prepare_config()?;
 		self.run_os_prober(&efi_target, &temp)?;
 
+		let mut grub_state = GrubState::load(state_path(&self.config, self.args), self.dry_run);
+
+		// If installing GRUB fails, the old config has to be put back, as the new
+		// one may refer to things the installed GRUB can't handle.
+		// Its signature too, which is removed when signing is turned off.
+		let backups = [
+			Backup::take(&conf)?,
+			Backup::take(&grub_state.path)?,
+			Backup::take(&with_suffix(&conf, ".sig"))?,
+		];
+
 		// Atomically switch to the new config
-		fs::rename(&temp, &conf)
-			.with_context(|| format!("Cannot rename {} to {}", temp.display(), conf.display()))?;
+		durable_rename(&temp, &conf)?;
 
-		self.remove_old_kernels()?;
+		if self.args.check_config {
+			if let Err(e) = self.check_config(&conf) {
+				restore_all(&backups);
+				return Err(e.wrap_err("Restored the previous grub.cfg"));
+			}
+		}
+
+		if let Err(e) = self.sign_files(&conf) {
+			restore_all(&backups);
+			return Err(e.wrap_err("Restored the previous grub.cfg"));
+		}
 
-		let mut grub_state = GrubState::load(&self.config);
+		// GRUB itself is left alone, as is its state, so it's still reinstalled
+		// on the next full run if needed.
+		if self.args.entries_only {
+			self.remove_old_kernels()?;
+			return Ok(self);
+		}
 
-		if grub_state.update(&self.config, &efi_target) {
+		// Installing to removable media or into an image isn't recorded in the
+		// state, so it's done whenever anything changed since the last run.
+		// grub.cfg is regenerated regardless, as some of what goes into it (the
+		// secrets, what os-prober finds) can't be hashed beforehand.
+		let inputs_hash = Some(self.inputs_hash()?);
+		let inputs_changed = self.args.force || grub_state.inputs_hash != inputs_hash;
+		let dirty = grub_state.update(&self.config, &efi_target, &self.bootloader_id)
+			|| (inputs_changed && (self.args.removable || self.args.image_root.is_some()));
+		if dirty || self.args.force_reinstall {
 			if std::env::var("NIXOS_INSTALL_GRUB").as_deref() == Ok("1") {
 				eprintln!("NIXOS_INSTALL_GRUB env var deprecated, use NIXOS_INSTALL_BOOTLOADER");
 				std::env::set_var("NIXOS_INSTALL_BOOTLOADER", "1");
 			}
 
-			self.install_bios(&efi_target)?;
-			self.install_efi(&efi_target)?;
+			let installed = self
+				.install_bios(&efi_target)
+				.and_then(|()| self.install_efi(&efi_target));
 
+			if let Err(e) = installed {
+				restore_all(&backups);
+				return Err(e.wrap_err("Failed to install GRUB, restored the previous grub.cfg"));
+			}
+
+			grub_state.record_install()?;
 			grub_state.save()?;
 		};
 
+		if grub_state.inputs_hash != inputs_hash {
+			grub_state.inputs_hash = inputs_hash;
+			grub_state.save()?;
+		}
+
+		// Only now that GRUB is known to be installed fine, as the old kernels
+		// are still needed for booting into an older generation otherwise.
+		self.remove_old_kernels()?;
+
 		Ok(self)
 	}
 
+	/// Hashes the config, the command line and the files it refers to, along
+	/// with the generations on the system, which together determine most of
+	/// the generated grub.cfg and how GRUB is installed.
+	fn inputs_hash(&self) -> Result<String> {
+		// This isn't stable across Rust versions, but that only means an
+		// unnecessary regeneration after an upgrade.
+		let mut hasher = DefaultHasher::new();
+
+		// Going through a `Value` sorts the keys of the maps in the config, which
+		// would otherwise hash in a different order every run.
+		serde_json::to_value(&self.config)?
+			.to_string()
+			.hash(&mut hasher);
+		// Plaintext passwords are left out of the above.
+		let mut users = self.config.users.0.iter().collect::<Vec<_>>();
+		users.sort_by_key(|&(name, _)| *name);
+		users.hash(&mut hasher);
+		self.appearance_hash()?.hash(&mut hasher);
+
+		let Args {
+			max_kernels,
+			image_root,
+			output,
+			removable,
+			no_nvram,
+			..
+		} = self.args;
+		(max_kernels, image_root, output, removable, no_nvram).hash(&mut hasher);
+		self.bootloader_id.hash(&mut hasher);
+		fs::canonicalize(self.default_config)?.hash(&mut hasher);
+
+		let profiles_dir = self.config.profiles_dir();
+		for dir in [
+			profiles_dir.to_owned(),
+			profiles_dir.join("system-profiles"),
+		] {
+			let Ok(entries) = fs::read_dir(dir) else {
+				continue;
+			};
+			let mut links = entries
+				.filter_map(|e| {
+					let path = e.ok()?.path();
+					let target = fs::read_link(&path).ok()?;
+					Some((path, target))
+				})
+				.collect::<Vec<_>>();
+			links.sort();
+			links.hash(&mut hasher);
+		}
+
+		Ok(format!("{:016x}", hasher.finish()))
+	}
+
+	fn grub_cfg_path(&self) -> PathBuf {
+		grub_cfg_path(&self.config, self.args)
+	}
+
+	/// Prints how the installed GRUB's state differs from the config, and
+	/// therefore whether `install` would reinstall GRUB.
+	pub fn compare_state(&self) -> Result<()> {
+		let efi_target = EfiTarget::deduce(&self.config)?;
+		let grub_state = GrubState::load(state_path(&self.config, self.args), true);
+		let changes = grub_state.changes(&GrubState::from_config(
+			&self.config,
+			&efi_target,
+			&self.bootloader_id,
+		));
+
+		for (field, old, new) in &changes {
+			println!("{field}: {old:?} -> {new:?}");
+		}
+
+		if changes.is_empty() {
+			println!("state unchanged: GRUB will not be reinstalled");
+		} else {
+			println!("state changed: GRUB will be reinstalled");
+		}
+
+		Ok(())
+	}
+
 	fn append_prepare_config(&self) -> Result<()> {
-		let extra_prepare_config = self
-			.config
-			.extra_prepare_config
-			.replace("@bootPath@", &self.config.boot_path.to_string_lossy());
+		let extra_prepare_config = self.substitute(self.config.extra_prepare_config);
 
-		if !extra_prepare_config.is_empty() {
-			Command::new(self.config.shell)
-				.arg("-c")
-				.arg(extra_prepare_config)
-				.status()?;
+		if extra_prepare_config.is_empty() {
+			return Ok(());
+		}
+
+		let mut child = self
+			.command(self.shell())
+			.arg("-c")
+			.arg(extra_prepare_config)
+			.spawn()
+			.context("Failed to run extraPrepareConfig")?;
+
+		let status = match self.args.prepare_timeout {
+			Some(timeout) => {
+				let deadline = Instant::now() + timeout;
+				loop {
+					if let Some(status) = child.try_wait()? {
+						break status;
+					}
+					if Instant::now() >= deadline {
+						// Already exiting by itself is fine too.
+						let _ = child.kill();
+						let _ = child.wait();
+						bail!(
+							"extraPrepareConfig did not finish within {}s",
+							timeout.as_secs()
+						);
+					}
+					thread::sleep(Duration::from_millis(100));
+				}
+			}
+			None => child.wait()?,
+		};
+
+		// Whatever it prepares, e.g. mounting the ESP, is likely needed to install.
+		if !status.success() {
+			bail!("extraPrepareConfig failed ({status})");
 		}
 
 		Ok(())
 	}
 
+	/// Has grub-script-check parse the written grub.cfg, so a syntax error is
+	/// caught here rather than by GRUB at boot.
+	fn check_config(&self, conf: &Path) -> Result<()> {
+		let Some(grub) = self.config.grub.or(self.config.grub_efi) else {
+			bail!(
+				"`--check-config` needs grub-script-check, but neither `grub` nor `grubEfi` is set"
+			)
+		};
+
+		let check = grub.join("bin/grub-script-check");
+		let output = self
+			.command(&check)
+			.arg(conf)
+			.output()
+			.with_context(|| format!("Failed to execute {}", check.display()))?;
+
+		if !output.status.success() {
+			bail!(
+				"{} is not a valid GRUB script ({}){}",
+				conf.display(),
+				output.status,
+				captured_output(&output.stderr)
+			);
+		}
+		Ok(())
+	}
+
 	fn run_os_prober(&self, efi_target: &EfiTarget, temp: &Path) -> Result<()> {
-		if self.config.use_os_prober {
+		if !self.config.use_os_prober {
 			return Ok(());
 		}
 
@@ -78,7 +302,7 @@ impl Builder<'_> {
 			EfiTarget::Neither => todo!("This is unhandled in the Perl version!!"),
 		};
 
-		let mut cmd = Command::new(self.config.shell);
+		let mut cmd = self.command(self.shell());
 		cmd.arg("-c").arg(format!(
 			"pkgdatadir={target}/share/grub {target}/etc/grub.d/30_os-prober >> {temp}",
 			target = target_package.display(),
@@ -94,9 +318,81 @@ impl Builder<'_> {
 		Ok(())
 	}
 
+	fn sign_files(&mut self, conf: &Path) -> Result<()> {
+		let Some(key) = self.config.sign_config else {
+			// GRUB would still check signatures left from when it was signed, which
+			// no longer match. Those of kernels go with the obsolete kernels.
+			for file in std::iter::once(conf).chain(self.written.iter().map(PathBuf::as_path)) {
+				let sig = with_suffix(file, ".sig");
+				match fs::remove_file(&sig) {
+					Err(e) if e.kind() != io::ErrorKind::NotFound => {
+						return Err(e).with_context(|| format!("Cannot remove {}", sig.display()));
+					}
+					_ => {}
+				}
+			}
+			return Ok(());
+		};
+
+		// Use a throwaway keyring so we don't depend on (or pollute) root's.
+		let home = tempfile::tempdir().context("Failed to create temporary GnuPG home")?;
+
+		let status = self
+			.command("gpg")
+			.arg("--homedir")
+			.arg(home.path())
+			.arg("--batch")
+			.arg("--import")
+			.arg(key)
+			.status()
+			.context("Failed to execute gpg")?;
+		if !status.success() {
+			bail!("Failed to import signing key {} ({status})", key.display());
+		}
+
+		let mut files = vec![conf.to_owned()];
+		files.extend(self.written.iter().cloned());
+		if self.config.check_signatures {
+			// GRUB verifies every file it loads, so the kernels and initrds need
+			// signatures too.
+			files.extend(self.copied.iter().cloned());
+		}
+
+		for file in files {
+			let sig = with_suffix(&file, ".sig");
+
+			let status = self
+				.command("gpg")
+				.arg("--homedir")
+				.arg(home.path())
+				.arg("--batch")
+				.arg("--yes")
+				.arg("--detach-sign")
+				.arg("--output")
+				.arg(&sig)
+				.arg(&file)
+				.status()
+				.context("Failed to execute gpg")?;
+			if !status.success() {
+				bail!("Failed to sign {} ({status})", file.display());
+			}
+
+			// Keep signatures of kernels from being removed as obsolete.
+			self.copied.insert(sig);
+		}
+
+		Ok(())
+	}
+
 	fn remove_old_kernels(&self) -> Result<()> {
 		// Remove obsolete files from $bootPath/kernels
-		for file in fs::read_dir(self.config.boot_path.join("kernels"))? {
+		let kernels = match fs::read_dir(self.boot_dir.join("kernels")) {
+			Ok(kernels) => kernels,
+			// A fresh /boot without any copied kernels has nothing to remove.
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		};
+		for file in kernels {
 			let file = file?;
 			let path = file.path();
 
@@ -118,18 +414,36 @@ impl Builder<'_> {
 
 		// install a symlink so that grub can detect the boot drive
 		let tmp_dir = tempfile::tempdir().context("Failed to create temporary space")?;
-		symlink(self.config.boot_path, tmp_dir.path().join("boot"))
-			.with_context(|| format!("Failed to symlink {}/boot", tmp_dir.path().display()))?;
+		symlink(
+			self.args.in_image(self.config.boot_path),
+			tmp_dir.path().join("boot"),
+		)
+		.with_context(|| format!("Failed to symlink {}/boot", tmp_dir.path().display()))?;
+
+		let install = bios.join("sbin/grub-install");
+		let devices = self
+			.config
+			.devices
+			.iter()
+			.filter(|&&dev| dev != Path::new("nodev"))
+			.collect::<Vec<_>>();
 
-		for dev in &self.config.devices {
-			if *dev == Path::new("nodev") {
-				continue;
+		// grub-install's own errors for these are rather confusing.
+		for dev in &devices {
+			let metadata = fs::metadata(dev)
+				.with_context(|| format!("Cannot install GRUB on {}", dev.display()))?;
+			if !metadata.file_type().is_block_device() {
+				bail!(
+					"Cannot install GRUB on {}: not a block device, check `devices` for typos",
+					dev.display()
+				);
 			}
+		}
 
+		install_on_each(&devices, |dev| {
 			eprintln!("installing the GRUB 2 boot loader on {}...", dev.display());
 
-			let install = bios.join("sbin/grub-install");
-			let mut cmd = Command::new(&install);
+			let mut cmd = self.command(&install);
 			cmd.arg("--recheck")
 				.arg(format!("--root-directory={}", tmp_dir.path().display()))
 				.arg(dev.canonicalize()?)
@@ -139,20 +453,20 @@ impl Builder<'_> {
 				cmd.arg("--force");
 			}
 			if let Some(target) = bios_target {
-				cmd.arg(format!("--target={}", target.display()));
+				cmd.arg(format!("--target={target}"));
 			}
-			let status = cmd.status()?;
+			let output = self.run_grub_install(&mut cmd)?;
 
-			if !status.success() {
+			if !output.status.success() {
 				bail!(
-					"{}: installation of GRUB on {} failed: ({status})",
-					install.display(),
-					dev.display()
+					"exited with {}{}",
+					output.status,
+					captured_output(&output.stderr)
 				);
 			}
-		}
-
-		Ok(())
+			Ok(())
+		})
+		.wrap_err_with(|| format!("Failed to run {}", install.display()))
 	}
 
 	fn install_efi(&self, efi_target: &EfiTarget) -> Result<()> {
@@ -160,64 +474,327 @@ impl Builder<'_> {
 			return Ok(());
 		};
 
+		// With redundant ESPs, each of them gets its own full install so the
+		// machine can still boot off any one of them.
+		for &mount_point in &self.config.efi_sys_mount_points.0 {
+			self.install_efi_into(efi, efi_target, mount_point)?;
+		}
+
+		Ok(())
+	}
+
+	fn install_efi_into(&self, efi: &Path, efi_target: &str, mount_point: &Path) -> Result<()> {
 		eprintln!(
 			"installing the GRUB 2 boot loader into {}...",
-			self.config.efi_sys_mount_point.display()
+			mount_point.display()
 		);
 
+		let removable = self.args.removable
+			|| (!self.config.can_touch_efi_variables && self.config.efi_install_as_removable);
+
+		let owner_marker = self
+			.args
+			.in_image(mount_point)
+			.join("EFI")
+			.join(&self.bootloader_id)
+			.join(EFI_OWNER_MARKER);
+		// The removable media path is meant to be shared, e.g. by whatever system
+		// last installed to a USB stick, and isn't under the bootloader ID anyway.
+		let machine_id = if removable { None } else { self.machine_id() };
+		if let Some(machine_id) = &machine_id {
+			self.check_efi_owner(&owner_marker, machine_id);
+		}
+
 		let install = efi.join("sbin/grub-install");
-		let mut cmd = Command::new(&install);
+		let mut cmd = self.command(&install);
 		cmd.arg("--recheck")
-			.arg(format!("--target={}", efi_target.display()))
+			.arg(format!("--target={efi_target}"))
 			.arg(format!(
 				"--boot-directory={}",
-				self.config.boot_path.display()
+				self.args.in_image(self.config.boot_path).display()
 			))
 			.arg(format!(
 				"--efi-directory={}",
-				self.config.efi_sys_mount_point.display()
+				self.args.in_image(mount_point).display()
 			))
 			.args(&self.config.extra_grub_install_args);
 
 		if self.config.force_install {
 			cmd.arg("--force");
 		}
-		cmd.arg(format!("--bootloader-id={}", self.config.bootloader_id));
+		cmd.arg(format!("--bootloader-id={}", self.bootloader_id));
+
+		// The command line takes precedence over the config, so a disk can be
+		// installed to for use in another machine. The EFI variables are the
+		// running machine's, so an image never gets to touch them.
+		let no_nvram = self.args.no_nvram
+			|| self.args.removable
+			|| self.args.image_root.is_some()
+			|| !self.config.can_touch_efi_variables;
 
-		if !self.config.can_touch_efi_variables {
+		if no_nvram {
 			cmd.arg("--no-nvram");
-			if self.config.efi_install_as_removable {
-				cmd.arg("--removable");
-			}
+		}
+		if removable {
+			cmd.arg("--removable");
 		}
 
-		let status = cmd.status()?;
+		let output = self.run_grub_install(&mut cmd)?;
 
-		if !status.success() {
+		if !output.status.success() {
 			bail!(
-				"{}: installation of GRUB EFI into {} failed: ({status})",
+				"{}: installation of GRUB EFI into {} failed: ({}){}",
 				install.display(),
-				self.config.efi_sys_mount_point.display()
+				mount_point.display(),
+				output.status,
+				captured_output(&output.stderr)
 			);
 		}
 
+		// Not fatal: it's only used to detect collisions on the next install.
+		if let Some(machine_id) = machine_id {
+			if let Err(e) = fs::write(&owner_marker, machine_id) {
+				eprintln!("warning: failed to write {}: {e}", owner_marker.display());
+			}
+		}
+
 		Ok(())
 	}
+
+	/// Runs grub-install, retrying it with a growing delay, as it occasionally
+	/// fails when devices are still being enumerated.
+	fn run_grub_install(&self, cmd: &mut Command) -> Result<Output> {
+		let attempts = self.args.install_attempts;
+		let mut attempt = 1;
+
+		loop {
+			let output = cmd.output()?;
+			let status = output.status;
+
+			if status.success() {
+				if self.args.verbose {
+					eprint!("{}", String::from_utf8_lossy(&output.stdout));
+					eprint!("{}", String::from_utf8_lossy(&output.stderr));
+				}
+				return Ok(output);
+			}
+			if attempt >= attempts {
+				return Ok(output);
+			}
+
+			let delay = Duration::from_secs(attempt.into());
+			eprintln!(
+				"warning: grub-install failed ({status}), retrying in {}s (attempt {attempt} of \
+				 {attempts})",
+				delay.as_secs()
+			);
+			thread::sleep(delay);
+			attempt += 1;
+		}
+	}
+
+	/// Identifies the system being installed, to tell it apart from others
+	/// sharing the ESP. Unlike e.g. the hostname, the machine ID differs even
+	/// between systems installed side by side on the same machine.
+	fn machine_id(&self) -> Option<String> {
+		let id = fs::read_to_string(self.args.in_image(Path::new("/etc/machine-id"))).ok()?;
+		let id = id.trim();
+		(!id.is_empty()).then(|| id.to_owned())
+	}
+
+	/// Warns if another system has already installed GRUB under the same
+	/// bootloader ID, as the two installs would keep overwriting each other.
+	fn check_efi_owner(&self, owner_marker: &Path, machine_id: &str) {
+		let Ok(owner) = fs::read_to_string(owner_marker) else {
+			return;
+		};
+		let owner = owner.trim();
+
+		if owner != machine_id {
+			eprintln!(
+				"warning: {} was installed by a different system ({owner}); installing GRUB with \
+				 the same bootloader ID `{}` will overwrite it",
+				owner_marker.parent().unwrap_or(owner_marker).display(),
+				self.bootloader_id
+			);
+			eprintln!(" note: each system sharing an ESP needs its own bootloader ID");
+		}
+	}
+}
+
+/// Where grub.cfg is written to.
+pub fn grub_cfg_path(config: &Config, args: &Args) -> PathBuf {
+	match &args.output {
+		Some(output) => output.clone(),
+		None => args.in_image(config.boot_path).join("grub/grub.cfg"),
+	}
+}
+
+/// Where what's known about the installed GRUB is kept, to tell whether it
+/// has to be reinstalled.
+pub fn state_path(config: &Config, args: &Args) -> PathBuf {
+	args.in_image(config.boot_path).join("grub/state")
 }
 
+/// Formats a failed command's output for an error message, keeping only the
+/// end of it, as that's where the actual error usually is.
+fn captured_output(output: &[u8]) -> String {
+	const MAX_LINES: usize = 20;
+
+	let output = String::from_utf8_lossy(output);
+	let lines = output.trim_end().lines().collect::<Vec<_>>();
+	if lines.is_empty() {
+		return String::new();
+	}
+
+	let omitted = lines.len().saturating_sub(MAX_LINES);
+	let mut formatted = String::from("\n");
+	if omitted > 0 {
+		formatted.push_str(&format!("  [... {omitted} earlier lines omitted]\n"));
+	}
+	for line in &lines[omitted..] {
+		formatted.push_str(&format!("  {line}\n"));
+	}
+	formatted
+}
+
+/// Puts back everything backed up before the install, warning about whatever
+/// can't be.
+fn restore_all(backups: &[Backup]) {
+	for backup in backups {
+		if let Err(e) = backup.restore() {
+			eprintln!("warning: {e:#}");
+		}
+	}
+}
+
+/// Runs `install` for every device at once, as grub-install can take a while
+/// on each. They all copy the same modules into the same /boot, which is why
+/// sharing its root directory is fine. A failure doesn't stop the others, so
+/// the user gets to know the state of every device.
+fn install_on_each<D>(devices: &[D], install: impl Fn(&Path) -> Result<()> + Sync) -> Result<()>
+where
+	D: AsRef<Path> + Sync,
+{
+	let results = devices
+		.par_iter()
+		.map(|dev| install(dev.as_ref()))
+		.collect::<Vec<_>>();
+
+	if results.iter().all(Result::is_ok) {
+		return Ok(());
+	}
+
+	let mut report = String::new();
+	for (dev, result) in devices.iter().zip(&results) {
+		let dev = dev.as_ref().display();
+		match result {
+			Ok(()) => writeln!(&mut report, "  {dev}: ok")?,
+			Err(e) => writeln!(&mut report, "  {dev}: failed: {e:#}")?,
+		}
+	}
+	bail!("installation of GRUB failed on some devices:\n{report}");
+}
+
+/// Appends a suffix to a path's file name, e.g. `grub.cfg` -> `grub.cfg.sig`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut path = path.to_owned().into_os_string();
+	path.push(suffix);
+	PathBuf::from(path)
+}
+
+/// A file's contents from before it got replaced, to be put back if the
+/// replacement has to be undone.
+struct Backup {
+	path: PathBuf,
+	/// The contents and mode of the file, or `None` when it didn't exist.
+	contents: Option<(Vec<u8>, u32)>,
+}
+impl Backup {
+	fn take(path: &Path) -> Result<Self> {
+		let contents = match fs::read(path).and_then(|c| Ok((c, fs::metadata(path)?))) {
+			Ok((contents, metadata)) => Some((contents, metadata.permissions().mode())),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+			Err(e) => return Err(e).with_context(|| format!("Cannot back up {}", path.display())),
+		};
+
+		Ok(Self {
+			path: path.to_owned(),
+			contents,
+		})
+	}
+
+	fn restore(&self) -> Result<()> {
+		let Some((contents, mode)) = &self.contents else {
+			return match fs::remove_file(&self.path) {
+				Err(e) if e.kind() != io::ErrorKind::NotFound => {
+					Err(e).with_context(|| format!("Cannot remove {}", self.path.display()))
+				}
+				_ => Ok(()),
+			};
+		};
+
+		// The mode is set before writing anything, as it may be e.g. a grub.cfg
+		// with plaintext passwords that only root may read.
+		let temp = with_suffix(&self.path, ".tmp");
+		fs::OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.mode(*mode)
+			.open(&temp)
+			.and_then(|mut file| {
+				file.set_permissions(fs::Permissions::from_mode(*mode))?;
+				file.write_all(contents)
+			})
+			.with_context(|| format!("Cannot restore {}", self.path.display()))?;
+		durable_rename(&temp, &self.path)
+	}
+}
+
+/// Renames a freshly written file into place, making sure both its contents
+/// and the rename itself have hit the disk, so a crash can't leave behind an
+/// empty or missing file.
+fn durable_rename(temp: &Path, path: &Path) -> Result<()> {
+	fs::File::open(temp)
+		.and_then(|f| f.sync_all())
+		.with_context(|| format!("Cannot sync {}", temp.display()))?;
+
+	fs::rename(temp, path)
+		.with_context(|| format!("Cannot rename {} to {}", temp.display(), path.display()))?;
+
+	// The rename is only durable once the directory entry is.
+	if let Some(parent) = path.parent() {
+		let parent = if parent.as_os_str().is_empty() {
+			Path::new(".")
+		} else {
+			parent
+		};
+		fs::File::open(parent)
+			.and_then(|d| d.sync_all())
+			.with_context(|| format!("Cannot sync {}", parent.display()))?;
+	}
+
+	Ok(())
+}
+
+/// Name of the file recording which system installed GRUB into an EFI
+/// directory, by its machine ID.
+const EFI_OWNER_MARKER: &str = "install-grub-owner";
+
 enum EfiTarget<'a> {
 	Both {
 		bios: &'a Path,
-		bios_target: &'a Path,
+		bios_target: &'a str,
 		efi: &'a Path,
-		efi_target: &'a Path,
+		efi_target: &'a str,
 	},
 	BiosOnly {
 		bios: &'a Path,
 	},
 	EfiOnly {
 		efi: &'a Path,
-		efi_target: &'a Path,
+		efi_target: &'a str,
 	},
 	Neither,
 }
@@ -231,6 +808,9 @@ impl<'a> EfiTarget<'a> {
 			..
 		} = config;
 
+		let grub_target = grub_target.map(|t| t.0);
+		let grub_target_efi = grub_target_efi.map(|t| t.0);
+
 		match (grub, grub_efi, grub_target, grub_target_efi) {
 			(Some(bios), Some(efi), Some(bios_target), Some(efi_target)) => Ok(Self::Both {
 				bios,
@@ -238,9 +818,13 @@ impl<'a> EfiTarget<'a> {
 				efi,
 				efi_target,
 			}),
-			(Some(_), Some(_), _, _) => bail!(
-				"EFI can only be installed when target is set; a target is also required then for \
-				 non-EFI grub"
+			(Some(_), Some(_), None, _) => bail!(
+				"`grubTarget` must be set (typically to `i386-pc`) when installing both BIOS and \
+				 EFI GRUB"
+			),
+			(Some(_), Some(_), _, None) => bail!(
+				"`grubTargetEfi` must be set (typically to `x86_64-efi`) when installing both \
+				 BIOS and EFI GRUB"
 			),
 			// TODO:
 			// It would be safer to disallow non-EFI grub installation if no target is
@@ -249,15 +833,22 @@ impl<'a> EfiTarget<'a> {
 			// on the availability of a EFI partition.
 			// However, it seems as auto-detection is currently relied on for non-x86_64 and
 			// non-i386 architectures in NixOS. That would have to be fixed in the nixos
-			// modules first.
+			// modules first. Until then, it can be opted into with
+			// `requireExplicitTarget`.
+			(Some(_), None, None, _) if config.require_explicit_target == Some(true) => bail!(
+				"`grubTarget` must be set (typically to `i386-pc`), as `requireExplicitTarget` \
+				 forbids letting GRUB detect it"
+			),
 			(Some(bios), None, _, _) => Ok(Self::BiosOnly { bios }),
 			(None, Some(efi), _, Some(efi_target)) => Ok(Self::EfiOnly { efi, efi_target }),
-			(None, Some(_), _, _) => bail!("EFI can only be installed when target is set"),
+			(None, Some(_), _, None) => bail!(
+				"`grubTargetEfi` must be set (typically to `x86_64-efi`) when installing EFI GRUB"
+			),
 			(None, None, _, _) => Ok(Self::Neither),
 		}
 	}
 
-	fn efi(&self) -> Option<(&Path, &Path)> {
+	fn efi(&self) -> Option<(&Path, &str)> {
 		match self {
 			Self::Both {
 				efi, efi_target, ..
@@ -267,7 +858,7 @@ impl<'a> EfiTarget<'a> {
 		}
 	}
 
-	fn bios(&self) -> Option<(&Path, Option<&Path>)> {
+	fn bios(&self) -> Option<(&Path, Option<&str>)> {
 		match self {
 			Self::Both {
 				bios, bios_target, ..
@@ -295,15 +886,70 @@ struct GrubState {
 	version: String,
 	efi: String,
 	devices: Vec<PathBuf>,
-	efi_mount_point: PathBuf,
+	efi_mount_points: Vec<PathBuf>,
 	extra_grub_install_args: Vec<String>,
+	bootloader_id: String,
+	/// When GRUB was last installed, as an RFC 3339 timestamp. Only for
+	/// diagnostics.
+	last_installed: Option<String>,
+	/// Hash of the inputs grub.cfg was last generated from.
+	inputs_hash: Option<String>,
+
+	/// The format the state was read in. Always [`STATE_SCHEMA_VERSION`] when
+	/// saving.
+	schema_version: u32,
 }
 impl GrubState {
-	fn load(config: &Config) -> Self {
-		let path = config.boot_path.join("grub/state");
-		let state = Self::parse(&path).unwrap_or_default();
+	/// Loads the state of the installed GRUB, upgrading the state file to the
+	/// current format unless `dry_run` is set.
+	fn load(path: PathBuf, dry_run: bool) -> Self {
+		if !path.exists() {
+			// GRUB simply hasn't been installed by us before.
+			return Self {
+				path,
+				..Default::default()
+			};
+		}
 
-		Self { path, ..state }
+		let Some(state) = Self::parse(&path) else {
+			// This forces a reinstall, so it shouldn't go unnoticed.
+			eprintln!(
+				"warning: {} is corrupt, treating GRUB as not installed",
+				path.display()
+			);
+			if !dry_run {
+				let aside = path.with_extension("corrupt");
+				match fs::copy(&path, &aside) {
+					Ok(_) => eprintln!(" note: a copy of it was kept at {}", aside.display()),
+					Err(e) => eprintln!("warning: failed to copy it to {}: {e}", aside.display()),
+				}
+			}
+
+			return Self {
+				path,
+				..Default::default()
+			};
+		};
+		let mut state = Self { path, ..state };
+
+		if state.schema_version < STATE_SCHEMA_VERSION {
+			state.migrate();
+
+			if !dry_run {
+				if let Err(e) = state.save() {
+					eprintln!("warning: failed to upgrade {}: {e:#}", state.path.display());
+				}
+			}
+		}
+
+		state
+	}
+
+	/// Brings state parsed from an older format up to date.
+	fn migrate(&mut self) {
+		// Version 0 had no schema version and possibly no JSON at all, whose
+		// fields all have defaults, so there's nothing to convert.
+		self.schema_version = STATE_SCHEMA_VERSION;
 	}
 
 	fn parse(path: &Path) -> Option<Self> {
@@ -319,7 +965,12 @@ impl GrubState {
 			.split(',')
 			.map(PathBuf::from)
 			.collect::<Vec<_>>();
-		let efi_mount_point = PathBuf::from(lines.next()?.ok()?);
+		let efi_mount_points = lines
+			.next()?
+			.ok()?
+			.split(',')
+			.map(PathBuf::from)
+			.collect::<Vec<_>>();
 
 		// Historically, arguments in the state file were one per each line, but that
 		// gets really messy when newlines are involved, structured arguments
@@ -334,7 +985,11 @@ impl GrubState {
 		};
 
 		let GrubJsonState {
+			schema_version,
 			extra_grub_install_args,
+			bootloader_id,
+			last_installed,
+			inputs_hash,
 		} = serde_json::from_str(json_state).ok()?;
 
 		Some(Self {
@@ -342,8 +997,12 @@ impl GrubState {
 			version,
 			efi,
 			devices,
-			efi_mount_point,
+			efi_mount_points,
 			extra_grub_install_args,
+			bootloader_id,
+			last_installed,
+			inputs_hash,
+			schema_version,
 			..Default::default()
 		})
 	}
@@ -365,85 +1024,580 @@ impl GrubState {
 					.collect::<Vec<_>>()
 					.join(",")
 			)?;
-			writeln!(&mut temp, "{}", self.efi_mount_point.display())?;
+			writeln!(
+				&mut temp,
+				"{}",
+				self.efi_mount_points
+					.iter()
+					.map(|s| s.to_string_lossy())
+					.collect::<Vec<_>>()
+					.join(",")
+			)?;
 
 			serde_json::to_writer(&mut temp, &GrubJsonState {
+				schema_version: STATE_SCHEMA_VERSION,
 				extra_grub_install_args: self.extra_grub_install_args.clone(),
+				bootloader_id: self.bootloader_id.clone(),
+				last_installed: self.last_installed.clone(),
+				inputs_hash: self.inputs_hash.clone(),
 			})?;
 			writeln!(&mut temp)?;
+			temp.flush()?;
 		}
 
-		fs::rename(&temp, &self.path).with_context(|| {
-			format!(
-				"Cannot rename {} to {}",
-				temp.display(),
-				self.path.display()
-			)
-		})?;
-
-		Ok(())
+		durable_rename(&temp, &self.path)
 	}
 
-	fn update(&mut self, config: &Config, efi_target: &EfiTarget) -> bool {
-		let mut dirty = false;
-
-		let device_targets = config.devices.iter().copied().collect::<HashSet<_>>();
-		let prev_device_targets = self
-			.devices
-			.iter()
-			.map(|p| p.as_ref())
-			.collect::<HashSet<_>>();
-
-		if !device_targets.is_disjoint(&prev_device_targets) {
-			dirty = true;
-			self.devices = config.devices.iter().map(|&p| p.to_owned()).collect();
-		}
-
-		let extra_grub_install_args = config
-			.extra_grub_install_args
-			.iter()
-			.copied()
-			.collect::<HashSet<_>>();
-		let prev_extra_grub_install_args = self
-			.extra_grub_install_args
-			.iter()
-			.map(|p| p.as_ref())
-			.collect::<HashSet<_>>();
-		if !extra_grub_install_args.is_disjoint(&prev_extra_grub_install_args) {
-			dirty = true;
-			self.extra_grub_install_args = config
+	/// The state GRUB would be in after installing it with the given config.
+	fn from_config(config: &Config, efi_target: &EfiTarget, bootloader_id: &str) -> Self {
+		Self {
+			name: config.full_name.to_owned(),
+			version: config.full_version.to_owned(),
+			efi: efi_target.to_str().to_owned(),
+			devices: config.devices.iter().map(|&p| p.to_owned()).collect(),
+			efi_mount_points: config
+				.efi_sys_mount_points
+				.0
+				.iter()
+				.map(|&p| p.to_owned())
+				.collect(),
+			extra_grub_install_args: config
 				.extra_grub_install_args
 				.iter()
 				.map(|&p| p.to_owned())
-				.collect();
+				.collect(),
+			bootloader_id: bootloader_id.to_owned(),
+			schema_version: STATE_SCHEMA_VERSION,
+			..Default::default()
 		}
+	}
 
-		if config.full_name != self.name {
-			dirty = true;
-			config.full_name.clone_into(&mut self.name);
+	/// Lists every field that differs from `new`, as `(field, old, new)`.
+	fn changes(&self, new: &Self) -> Vec<(&'static str, String, String)> {
+		fn list<T: AsRef<OsStr>>(items: &[T]) -> String {
+			items
+				.iter()
+				.map(|s| s.as_ref().to_string_lossy())
+				.collect::<Vec<_>>()
+				.join(",")
+		}
+		// Order doesn't matter for lists.
+		fn set<T: AsRef<OsStr>>(items: &[T]) -> HashSet<&OsStr> {
+			items.iter().map(AsRef::as_ref).collect()
+		}
+
+		let mut changes = vec![];
+
+		if self.name != new.name {
+			changes.push(("name", self.name.clone(), new.name.clone()));
+		}
+		if self.version != new.version {
+			changes.push(("version", self.version.clone(), new.version.clone()));
+		}
+		if self.efi != new.efi {
+			changes.push(("efi", self.efi.clone(), new.efi.clone()));
+		}
+		if set(&self.devices) != set(&new.devices) {
+			changes.push(("devices", list(&self.devices), list(&new.devices)));
 		}
-		if config.full_version != self.version {
-			dirty = true;
-			config.full_version.clone_into(&mut self.version);
+		if set(&self.extra_grub_install_args) != set(&new.extra_grub_install_args) {
+			changes.push((
+				"extraGrubInstallArgs",
+				list(&self.extra_grub_install_args),
+				list(&new.extra_grub_install_args),
+			));
 		}
-		if efi_target.to_str() != self.efi {
-			dirty = true;
-			efi_target.to_str().clone_into(&mut self.efi);
+		// Older state files don't record it, which shouldn't force a reinstall.
+		if !self.bootloader_id.is_empty() && self.bootloader_id != new.bootloader_id {
+			changes.push((
+				"bootloaderId",
+				self.bootloader_id.clone(),
+				new.bootloader_id.clone(),
+			));
 		}
-		if config.efi_sys_mount_point != self.efi_mount_point {
-			dirty = true;
-			config
-				.efi_sys_mount_point
-				.clone_into(&mut self.efi_mount_point);
+		if set(&self.efi_mount_points) != set(&new.efi_mount_points) {
+			changes.push((
+				"efiMountPoint",
+				list(&self.efi_mount_points),
+				list(&new.efi_mount_points),
+			));
 		}
 
+		changes
+	}
+
+	fn update(&mut self, config: &Config, efi_target: &EfiTarget, bootloader_id: &str) -> bool {
+		let new = Self::from_config(config, efi_target, bootloader_id);
+		let dirty = !self.changes(&new).is_empty();
+
+		*self = Self {
+			path: std::mem::take(&mut self.path),
+			last_installed: self.last_installed.take(),
+			inputs_hash: self.inputs_hash.take(),
+			..new
+		};
+
 		dirty
 	}
+
+	/// Notes down that GRUB has just been installed successfully.
+	fn record_install(&mut self) -> Result<()> {
+		let now = time::OffsetDateTime::now_utc()
+			.format(&time::format_description::well_known::Rfc3339)
+			.context("Failed to format the current time")?;
+		self.last_installed = Some(now);
+
+		Ok(())
+	}
 }
 
+/// The version of the state file's format, to be bumped whenever it changes
+/// along with a migration in [`GrubState::migrate`].
+const STATE_SCHEMA_VERSION: u32 = 1;
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 struct GrubJsonState {
+	/// Missing in state files from before versioning, i.e. version 0.
+	#[serde(default)]
+	schema_version: u32,
 	#[serde(default)]
 	extra_grub_install_args: Vec<String>,
+	#[serde(default)]
+	bootloader_id: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	last_installed: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	inputs_hash: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		os::unix::fs::PermissionsExt,
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Mutex,
+		},
+	};
+
+	use roxmltree::Document;
+
+	use super::*;
+	use crate::builder::testing::{boolean, int, list, string, Fixture};
+
+	/// Generates grub.cfg, without the menu entries, and installs GRUB.
+	fn install(fx: &Fixture) -> Result<()> {
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		fx.builder(config).users()?.default_entry()?.install()?;
+		Ok(())
+	}
+
+	fn grub_installs(fx: &Fixture) -> Vec<String> {
+		fx.commands()
+			.into_iter()
+			.filter(|c| c.starts_with("grub-install "))
+			.collect()
+	}
+
+	fn state() -> GrubState {
+		GrubState {
+			name: String::from("grub"),
+			version: String::from("2.12"),
+			efi: String::from("x86_64-efi"),
+			devices: vec![PathBuf::from("/dev/sda"), PathBuf::from("/dev/sdb")],
+			efi_mount_points: vec![PathBuf::from("/boot")],
+			extra_grub_install_args: vec![String::from("--modules=tpm")],
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn state_round_trips() {
+		let dir = tempfile::tempdir().unwrap();
+		let saved = GrubState {
+			path: dir.path().join("state"),
+			schema_version: STATE_SCHEMA_VERSION,
+			..state()
+		};
+		saved.save().unwrap();
+
+		let loaded = GrubState::parse(&saved.path).unwrap();
+
+		assert!(loaded.changes(&saved).is_empty());
+		assert_eq!(loaded.schema_version, STATE_SCHEMA_VERSION);
+	}
+
+	#[test]
+	fn upgrades_old_state() {
+		let fx = Fixture::new();
+		let path = fx.root().join("boot/grub/state");
+		let old = "grub\n2.12\nno\n/dev/sda\n/boot\n";
+		fs::write(&path, old).unwrap();
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		let state = GrubState::load(state_path(&config, &fx.args), true);
+		assert_eq!(state.efi, "no");
+		assert_eq!(state.devices, [PathBuf::from("/dev/sda")]);
+		assert_eq!(fs::read_to_string(&path).unwrap(), old);
+
+		GrubState::load(state_path(&config, &fx.args), false);
+		let state = GrubState::parse(&path).unwrap();
+		assert_eq!(state.schema_version, STATE_SCHEMA_VERSION);
+		assert_eq!(state.devices, [PathBuf::from("/dev/sda")]);
+
+		fs::write(&path, "grub\n").unwrap();
+		assert!(GrubState::parse(&path).is_none());
+	}
+
+	#[test]
+	fn state_changes() {
+		let old = state();
+
+		// Order doesn't matter for lists.
+		let mut new = state();
+		new.devices.reverse();
+		assert!(old.changes(&new).is_empty());
+
+		new.version = String::from("2.14");
+		new.devices.pop();
+		let fields = old
+			.changes(&new)
+			.into_iter()
+			.map(|(field, ..)| field)
+			.collect::<Vec<_>>();
+		assert_eq!(fields, ["version", "devices"]);
+	}
+
+	#[test]
+	fn runs_snippets_with_the_shell_override() {
+		let mut fx = Fixture::new();
+		let shell = fx.root().join("sh");
+		fs::write(
+			&shell,
+			format!(
+				"#!/bin/sh\necho \"$@\" > {}\nexec /bin/sh \"$@\"\n",
+				fx.root().join("shell-args").display()
+			),
+		)
+		.unwrap();
+		fs::set_permissions(&shell, fs::Permissions::from_mode(0o755)).unwrap();
+		fx.args.shell = Some(shell);
+		fx.set("extraPrepareConfig", string("touch @bootPath@/prepared"));
+
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+		fx.builder(config).append_prepare_config().unwrap();
+
+		let shell_args = fs::read_to_string(fx.root().join("shell-args")).unwrap();
+		assert!(shell_args.starts_with("-c touch "));
+		assert!(fx.root().join("boot/prepared").exists());
+	}
+
+	#[test]
+	fn runs_snippets_with_the_configured_path() {
+		let mut fx = Fixture::new();
+		let path = format!("/nowhere:{}", std::env::var("PATH").unwrap());
+		fx.set("path", string(&path));
+		fx.set(
+			"extraPrepareConfig",
+			string(&format!(
+				"echo \"$PATH\" > {}",
+				fx.root().join("prepare-path").display()
+			)),
+		);
+
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+		fx.builder(config).append_prepare_config().unwrap();
+
+		let prepare_path = fs::read_to_string(fx.root().join("prepare-path")).unwrap();
+		assert_eq!(prepare_path.trim_end(), path);
+		// Only for the commands it runs, not the rest of the process.
+		assert!(!std::env::var("PATH").unwrap().starts_with("/nowhere:"));
+	}
+
+	#[test]
+	fn captures_the_end_of_output() {
+		assert_eq!(captured_output(b""), "");
+		assert_eq!(captured_output(b"error: oops\n"), "\n  error: oops\n");
+
+		let output = (1..=25).map(|i| format!("line {i}\n")).collect::<String>();
+		let captured = captured_output(output.as_bytes());
+		assert!(captured.starts_with("\n  [... 5 earlier lines omitted]\n  line 6\n"));
+		assert!(captured.ends_with("  line 25\n"));
+	}
+
+	#[test]
+	fn restores_backups() {
+		let dir = tempfile::tempdir().unwrap();
+		let conf = dir.path().join("grub.cfg");
+		let state = dir.path().join("state");
+		fs::write(&conf, "old").unwrap();
+
+		let backups = [Backup::take(&conf).unwrap(), Backup::take(&state).unwrap()];
+		fs::write(&conf, "new").unwrap();
+		fs::write(&state, "new").unwrap();
+		for backup in &backups {
+			backup.restore().unwrap();
+		}
+
+		assert_eq!(fs::read_to_string(&conf).unwrap(), "old");
+		// It didn't exist before, so it shouldn't now either.
+		assert!(!state.exists());
+	}
+
+	#[test]
+	fn installs_efi_grub() {
+		let fx = Fixture::new();
+
+		install(&fx).unwrap();
+
+		let root = fx.root().display();
+		assert_eq!(grub_installs(&fx), [format!(
+			"grub-install --recheck --target=x86_64-efi --boot-directory={root}/boot \
+			 --efi-directory={root}/efi --bootloader-id=NixOS-boot --no-nvram"
+		)]);
+	}
+
+	#[test]
+	fn installs_efi_grub_with_config_flags() {
+		let mut fx = Fixture::new();
+		fx.set("canTouchEfiVariables", boolean(true));
+		fx.set("forceInstall", boolean(true));
+		fx.set("extraGrubInstallArgs", list(&[string("--modules=tpm")]));
+
+		install(&fx).unwrap();
+
+		let root = fx.root().display();
+		assert_eq!(grub_installs(&fx), [format!(
+			"grub-install --recheck --target=x86_64-efi --boot-directory={root}/boot \
+			 --efi-directory={root}/efi --modules=tpm --force --bootloader-id=NixOS-boot"
+		)]);
+	}
+
+	#[test]
+	fn installs_efi_grub_as_removable() {
+		let mut fx = Fixture::new();
+		fx.set("canTouchEfiVariables", boolean(true));
+		fx.args.removable = true;
+
+		install(&fx).unwrap();
+
+		let installs = grub_installs(&fx);
+		assert_eq!(installs.len(), 1);
+		assert!(installs[0].ends_with("--bootloader-id=NixOS-boot --no-nvram --removable"));
+	}
+
+	#[test]
+	fn leaves_owner_markers_alone_when_removable() {
+		let mut fx = Fixture::new();
+		let marker = fx.root().join("efi/EFI/NixOS-boot").join(EFI_OWNER_MARKER);
+		fs::create_dir_all(marker.parent().unwrap()).unwrap();
+		fs::write(&marker, "another-machine").unwrap();
+		fx.args.removable = true;
+
+		install(&fx).unwrap();
+
+		assert_eq!(fs::read_to_string(&marker).unwrap(), "another-machine");
+	}
+
+	#[test]
+	fn installs_into_every_esp() {
+		let mut fx = Fixture::new();
+		let esps = ["efi", "efi2"].map(|esp| fx.root().join(esp));
+		fs::create_dir_all(&esps[1]).unwrap();
+		fx.set(
+			"efiSysMountPoint",
+			list(&esps.each_ref().map(|esp| string(&esp.to_string_lossy()))),
+		);
+
+		install(&fx).unwrap();
+
+		let installs = grub_installs(&fx);
+		assert_eq!(installs.len(), 2);
+		for (install, esp) in installs.iter().zip(&esps) {
+			assert!(install.contains(&format!("--efi-directory={} ", esp.display())));
+		}
+	}
+
+	#[test]
+	fn retries_grub_install() {
+		let mut fx = Fixture::new();
+		fx.fail("grub-install");
+		fx.args.install_attempts = 2;
+
+		assert!(install(&fx).is_err());
+		assert_eq!(grub_installs(&fx).len(), 2);
+	}
+
+	#[test]
+	fn only_writes_entries() {
+		let mut fx = Fixture::new();
+		fx.args.entries_only = true;
+
+		install(&fx).unwrap();
+
+		assert!(fx.grub_cfg().contains("set timeout=5"));
+		assert!(grub_installs(&fx).is_empty());
+		assert!(!fx.root().join("boot/grub/state").exists());
+	}
+
+	#[test]
+	fn reinstalls_only_when_the_state_changed() {
+		let mut fx = Fixture::new();
+		install(&fx).unwrap();
+		install(&fx).unwrap();
+		assert_eq!(grub_installs(&fx).len(), 1);
+
+		fx.args.force_reinstall = true;
+		install(&fx).unwrap();
+		assert_eq!(grub_installs(&fx).len(), 2);
+	}
+
+	#[test]
+	fn reinstalls_removable_only_when_the_inputs_changed() {
+		let mut fx = Fixture::new();
+		fx.args.removable = true;
+		install(&fx).unwrap();
+		install(&fx).unwrap();
+		assert_eq!(grub_installs(&fx).len(), 1);
+
+		fx.set("timeout", int(10));
+		install(&fx).unwrap();
+		assert_eq!(grub_installs(&fx).len(), 2);
+
+		fx.args.force = true;
+		install(&fx).unwrap();
+		assert_eq!(grub_installs(&fx).len(), 3);
+	}
+
+	#[test]
+	fn installs_on_every_device() {
+		let devices = [Path::new("/dev/sda"), Path::new("/dev/sdb")];
+		let installed = Mutex::new(vec![]);
+
+		install_on_each(&devices, |dev| {
+			installed.lock().unwrap().push(dev.to_owned());
+			Ok(())
+		})
+		.unwrap();
+
+		let mut installed = installed.into_inner().unwrap();
+		installed.sort();
+		assert_eq!(installed, devices);
+	}
+
+	#[test]
+	fn reports_every_device_if_one_fails() {
+		let devices = [Path::new("/dev/sda"), Path::new("/dev/sdb")];
+		let attempts = AtomicUsize::new(0);
+
+		let err = install_on_each(&devices, |dev| {
+			attempts.fetch_add(1, Ordering::SeqCst);
+			if dev == Path::new("/dev/sda") {
+				bail!("exited with 1");
+			}
+			Ok(())
+		})
+		.unwrap_err();
+
+		assert_eq!(attempts.into_inner(), 2);
+		assert_eq!(
+			err.to_string(),
+			"installation of GRUB failed on some devices:\n  /dev/sda: failed: exited with 1\n  \
+			 /dev/sdb: ok\n"
+		);
+	}
+
+	#[test]
+	fn bios_devices_must_be_block_devices() {
+		let mut fx = Fixture::new();
+		let grub = fx.root().join("store/dddd-grub-2.12");
+		let device = fx.root().join("disk.img");
+		fs::write(&device, "").unwrap();
+		fx.set("grub", string(&grub.to_string_lossy()));
+		fx.set("grubTarget", string("i386-pc"));
+		fx.set("grubEfi", string(""));
+		fx.set("devices", list(&[string(&device.to_string_lossy())]));
+
+		let err = install(&fx).unwrap_err();
+
+		assert!(format!("{err:#}").contains("not a block device"));
+		assert!(grub_installs(&fx).is_empty());
+	}
+
+	#[test]
+	fn prints_where_it_writes_to() {
+		let mut fx = Fixture::new();
+		let xml = fx.xml();
+		let doc = Document::parse(&xml).unwrap();
+		let config = Config::new(&doc).unwrap();
+
+		install(&fx).unwrap();
+		assert_eq!(
+			grub_cfg_path(&config, &fx.args),
+			fx.root().join("boot/grub/grub.cfg")
+		);
+		assert!(grub_cfg_path(&config, &fx.args).exists());
+		assert!(state_path(&config, &fx.args).exists());
+
+		let output = fx.root().join("grub.cfg");
+		fx.args.output = Some(output.clone());
+		install(&fx).unwrap();
+		assert_eq!(grub_cfg_path(&config, &fx.args), output);
+		assert!(output.exists());
+	}
+
+	#[test]
+	fn restores_grub_cfg_if_installing_fails() {
+		let mut fx = Fixture::new();
+		install(&fx).unwrap();
+		let previous = fx.grub_cfg();
+
+		// Changing the GRUB version makes it reinstall.
+		fx.fail("grub-install");
+		fx.set("fullVersion", string("2.14"));
+		fx.set("timeout", int(10));
+		assert!(install(&fx).is_err());
+		assert_eq!(fx.grub_cfg(), previous);
+	}
+
+	#[test]
+	fn checks_the_written_config() {
+		let mut fx = Fixture::new();
+		fx.args.check_config = true;
+
+		install(&fx).unwrap();
+		let conf = fx.root().join("boot/grub/grub.cfg");
+		assert!(fx
+			.commands()
+			.contains(&format!("grub-script-check {}", conf.display())));
+
+		// A config that doesn't parse is replaced by the previous one.
+		let previous = fx.grub_cfg();
+		fx.fail("grub-script-check");
+		fx.set("timeout", int(10));
+		assert!(install(&fx).is_err());
+		assert_eq!(fx.grub_cfg(), previous);
+	}
+
+	#[test]
+	fn runs_os_prober_only_when_enabled() {
+		let mut fx = Fixture::new();
+
+		install(&fx).unwrap();
+		assert!(!fx.commands().iter().any(|c| c.starts_with("30_os-prober")));
+
+		fx.set("useOSProber", boolean(true));
+		install(&fx).unwrap();
+		assert!(fx.commands().iter().any(|c| c.starts_with("30_os-prober")));
+	}
 }
@@ -0,0 +1,268 @@
+//! A fake NixOS system in a temporary directory to run the builder against in
+//! tests: a store with a single generation, the profile pointing at it, an
+//! empty /boot and ESP, and a GRUB package that only records how it was run.
+
+// Each test only uses some of it.
+#![allow(dead_code)]
+
+use std::{
+	collections::{BTreeMap, HashSet},
+	fs,
+	os::unix::fs::{symlink, PermissionsExt},
+	path::{Path, PathBuf},
+};
+
+use tempfile::TempDir;
+
+use super::Builder;
+use crate::{args::Args, config::Config, grub::Grub};
+
+pub struct Fixture {
+	dir: TempDir,
+	pub args: Args,
+	attrs: BTreeMap<&'static str, String>,
+}
+impl Fixture {
+	pub fn new() -> Self {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path();
+
+		let kernel = root.join("store/aaaa-linux-6.6.1");
+		fs::create_dir_all(kernel.join("lib/modules/6.6.1")).unwrap();
+		fs::write(kernel.join("bzImage"), "kernel").unwrap();
+
+		let initrd = root.join("store/bbbb-initrd-linux-6.6.1");
+		fs::create_dir_all(&initrd).unwrap();
+		fs::write(initrd.join("initrd"), "initrd").unwrap();
+
+		let system = root.join("store/cccc-nixos-system-test-24.05");
+		fs::create_dir_all(system.join("specialisation")).unwrap();
+		symlink(kernel.join("bzImage"), system.join("kernel")).unwrap();
+		symlink(initrd.join("initrd"), system.join("initrd")).unwrap();
+		fs::write(system.join("init"), "").unwrap();
+		fs::write(system.join("kernel-params"), "loglevel=4").unwrap();
+		fs::write(system.join("nixos-version"), "24.05").unwrap();
+
+		fs::create_dir_all(root.join("profiles")).unwrap();
+		symlink(&system, root.join("profiles/system-1-link")).unwrap();
+		symlink("system-1-link", root.join("profiles/system")).unwrap();
+
+		// Builder::new would create boot/grub, and obsolete kernels are looked for
+		// in boot/kernels.
+		fs::create_dir_all(root.join("boot/grub")).unwrap();
+		fs::create_dir_all(root.join("boot/kernels")).unwrap();
+		fs::create_dir_all(root.join("efi")).unwrap();
+
+		let grub = root.join("store/dddd-grub-2.12");
+		for tool in [
+			"sbin/grub-install",
+			"bin/grub-script-check",
+			"etc/grub.d/30_os-prober",
+		] {
+			fake_tool(root, &grub.join(tool), "");
+		}
+		// Keeps what it was given, and outputs a hash like the real one does.
+		fake_tool(
+			root,
+			&grub.join("bin/grub-mkpasswd-pbkdf2"),
+			&format!(
+				"cat > {}\necho \"PBKDF2 hash of your password is \
+				 grub.pbkdf2.sha512.10000.AB.CD\"\n",
+				root.join("mkpasswd-input").display()
+			),
+		);
+
+		let path = |p: PathBuf| string(&p.to_string_lossy());
+		let mut attrs = BTreeMap::new();
+		for (key, value) in [
+			("distroName", string("NixOS")),
+			("grub", string("")),
+			("grubTarget", string("")),
+			("grubEfi", path(grub)),
+			("grubTargetEfi", string("x86_64-efi")),
+			("requireExplicitTarget", boolean(false)),
+			("extraConfig", string("")),
+			("extraPrepareConfig", string("")),
+			("extraPerEntryConfig", string("")),
+			("extraEntries", string("")),
+			("extraEntriesBeforeNixOS", boolean(false)),
+			("quirkEntries", list(&[])),
+			("allowCustomCfg", boolean(false)),
+			("splashImage", string("")),
+			("splashImageEfi", string("")),
+			("splashImageBios", string("")),
+			("splashMode", string("")),
+			("backgroundColor", string("")),
+			("colorNormal", string("")),
+			("colorHighlight", string("")),
+			("menuColorNormal", string("")),
+			("menuColorHighlight", string("")),
+			("entryOptions", string("--class nixos --unrestricted")),
+			("subEntryOptions", string("--class nixos")),
+			("entryClass", string("")),
+			("subEntryClass", string("")),
+			("dedupeKernelParams", boolean(false)),
+			("profilesDir", path(root.join("profiles"))),
+			("configurationLimit", int(100)),
+			("specialisationLimit", int(0)),
+			(
+				"profileConfigurationLimits",
+				String::from("<attrs></attrs>"),
+			),
+			("generationSort", string("generation")),
+			("excludeGenerations", list(&[])),
+			("groupGenerationsByMonth", boolean(false)),
+			("copyKernels", boolean(false)),
+			("preferHardlinks", boolean(false)),
+			("earlyInitrds", list(&[])),
+			("groupCurrentSpecialisations", boolean(false)),
+			("timeout", int(5)),
+			("timeoutStyle", string("menu")),
+			("hiddenTimeout", String::from("<null />")),
+			("default", string("0")),
+			("explicitPrefix", boolean(false)),
+			("fsIdentifier", string("provided")),
+			("bootPath", path(root.join("boot"))),
+			("storePath", path(root.join("store"))),
+			("gfxmodeEfi", string("auto")),
+			("gfxmodeBios", string("1024x768")),
+			("gfxpayloadEfi", string("keep")),
+			("gfxpayloadBios", string("text")),
+			("font", list(&[])),
+			("fontSize", int(16)),
+			("theme", string("")),
+			("shell", string("/bin/sh")),
+			// The fake tools are shell scripts, which still need e.g. `cat`.
+			("path", string(&std::env::var("PATH").unwrap_or_default())),
+			("users", String::from("<attrs></attrs>")),
+			("hashPlaintextPasswords", boolean(false)),
+			("useOSProber", boolean(false)),
+			("signConfig", string("")),
+			("checkSignatures", boolean(false)),
+			("canTouchEfiVariables", boolean(false)),
+			("efiInstallAsRemovable", boolean(false)),
+			("efiSysMountPoint", path(root.join("efi"))),
+			("bootloaderId", string("NixOS-boot")),
+			("forceInstall", boolean(false)),
+			("devices", list(&[string("nodev")])),
+			("extraGrubInstallArgs", list(&[])),
+			("fullName", string("grub-2.12")),
+			("fullVersion", string("2.12")),
+		] {
+			attrs.insert(key, value);
+		}
+
+		let args = Args {
+			config_file: root.join("config.xml"),
+			default_config: root.join("profiles/system"),
+			install_attempts: 1,
+			..Default::default()
+		};
+
+		Self { dir, args, attrs }
+	}
+
+	pub fn root(&self) -> &Path {
+		self.dir.path()
+	}
+
+	/// Overrides a key of the config, given as a Nix XML value, e.g. from
+	/// [`string`].
+	pub fn set(&mut self, key: &'static str, value: String) {
+		self.attrs.insert(key, value);
+	}
+
+	/// The config as `nix-instantiate --xml` would print it.
+	pub fn xml(&self) -> String {
+		let attrs = self
+			.attrs
+			.iter()
+			.map(|(key, value)| format!("<attr name=\"{key}\">{value}</attr>"))
+			.collect::<String>();
+		format!("<?xml version='1.0' encoding='utf-8'?>\n<expr><attrs>{attrs}</attrs></expr>")
+	}
+
+	pub fn grub_cfg(&self) -> String {
+		fs::read_to_string(self.root().join("boot/grub/grub.cfg")).unwrap()
+	}
+
+	/// Every command the fake GRUB tools were run with, in order.
+	pub fn commands(&self) -> Vec<String> {
+		fs::read_to_string(self.root().join("commands"))
+			.unwrap_or_default()
+			.lines()
+			.map(str::to_owned)
+			.collect()
+	}
+
+	/// Makes the fake tool of that name fail from now on.
+	pub fn fail(&self, tool: &str) {
+		fs::write(self.root().join(format!("fail-{tool}")), "").unwrap();
+	}
+
+	/// A builder for `config` and [`Self::args`].
+	///
+	/// The filesystems of /boot and the store aren't looked up, so grub.cfg has
+	/// no `search` lines and refers to files by their path as is.
+	pub fn builder<'a>(&'a self, config: Config<'a>) -> Builder<'a> {
+		fs::write(&self.args.config_file, self.xml()).unwrap();
+		let grub_store = (!config.copy_kernels).then(Grub::default);
+		let boot_dir = self.args.in_image(config.boot_path);
+		let bootloader_id = config.bootloader_id.to_owned();
+		Builder {
+			config,
+			args: &self.args,
+			grub_boot: Grub::default(),
+			grub_store,
+			submenus: vec![],
+			menu_entries: vec![],
+			boot_dir,
+			bootloader_id,
+			grub_secrets: None,
+			default_config: &self.args.default_config,
+			copied: HashSet::new(),
+			written: vec![],
+			kernel_copies: 0,
+			dry_run: self.args.dry_run,
+			inner: String::new(),
+		}
+	}
+}
+
+/// Writes a script that records how it was run to `commands`, and fails if
+/// `fail-<name>` exists, before running `body`.
+fn fake_tool(root: &Path, path: &Path, body: &str) {
+	fs::create_dir_all(path.parent().unwrap()).unwrap();
+	let name = path.file_name().unwrap().to_str().unwrap();
+	fs::write(
+		path,
+		format!(
+			"#!/bin/sh\necho \"{name} $*\" >> {log}\n[ -e {fail} ] && exit 1\n{body}exit 0\n",
+			log = root.join("commands").display(),
+			fail = root.join(format!("fail-{name}")).display(),
+		),
+	)
+	.unwrap();
+	fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+pub fn string(s: &str) -> String {
+	let s = s
+		.replace('&', "&amp;")
+		.replace('"', "&quot;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;");
+	format!("<string value=\"{s}\" />")
+}
+
+pub fn boolean(b: bool) -> String {
+	format!("<bool value=\"{b}\" />")
+}
+
+pub fn int(i: i64) -> String {
+	format!("<int value=\"{i}\" />")
+}
+
+pub fn list(items: &[String]) -> String {
+	format!("<list>{}</list>", items.concat())
+}
@@ -7,31 +7,56 @@ use crate::grub::FsIdentifier;
 
 config! {
   'a;
+  distro_name: &'a str => distroName = "NixOS",
   grub: Option<&'a Path> => grub,
-  grub_target: Option<&'a Path> => grubTarget,
+  grub_target: Option<GrubTarget<'a>> => grubTarget,
   grub_efi: Option<&'a Path> => grubEfi,
-  grub_target_efi: Option<&'a Path> => grubTargetEfi,
+  grub_target_efi: Option<GrubTarget<'a>> => grubTargetEfi,
+  require_explicit_target: Option<bool> => requireExplicitTarget,
 
   extra_config: &'a str => extraConfig,
   extra_prepare_config: &'a str => extraPrepareConfig,
   extra_per_entry_config: Option<&'a str> => extraPerEntryConfig,
   extra_entries: &'a str => extraEntries,
   extra_entries_before_nixos: bool => extraEntriesBeforeNixOS,
+  quirk_entries: Vec<Quirk<'a>> => quirkEntries = vec![],
+  allow_custom_cfg: bool => allowCustomCfg = false,
 
   splash_image: Option<&'a Path> => splashImage,
-  splash_mode: Option<&'a str> => splashMode,
+  splash_image_efi: Option<&'a Path> => splashImageEfi,
+  splash_image_bios: Option<&'a Path> => splashImageBios,
+  splash_mode: SplashMode => splashMode = SplashMode::Normal,
   background_color: Option<&'a str> => backgroundColor,
 
+  color_normal: Option<&'a str> => colorNormal,
+  color_highlight: Option<&'a str> => colorHighlight,
+  menu_color_normal: Option<&'a str> => menuColorNormal,
+  menu_color_highlight: Option<&'a str> => menuColorHighlight,
+
   entry_options: &'a str => entryOptions,
   sub_entry_options: &'a str => subEntryOptions,
+  entry_class: Option<&'a str> => entryClass,
+  sub_entry_class: Option<&'a str> => subEntryClass,
+  dedupe_kernel_params: bool => dedupeKernelParams = false,
 
+  profiles_dir: Option<&'a Path> => profilesDir,
   configuration_limit: usize => configurationLimit,
+  specialisation_limit: Option<usize> => specialisationLimit,
+  profile_configuration_limits: HashMap<&'a str, usize> => profileConfigurationLimits = HashMap::new(),
+  generation_sort: GenerationSort => generationSort = GenerationSort::Generation,
+  exclude_generations: Vec<u32> => excludeGenerations = vec![],
+  group_generations_by_month: bool => groupGenerationsByMonth = false,
   copy_kernels: bool => copyKernels,
+  prefer_hardlinks: bool => preferHardlinks = false,
+  early_initrds: Vec<&'a Path> => earlyInitrds = vec![],
+  group_current_specialisations: bool => groupCurrentSpecialisations = false,
 
   timeout: u32 => timeout,
   timeout_style: &'a str => timeoutStyle,
+  hidden_timeout: Option<u32> => hiddenTimeout,
 
   default_entry: &'a str => default,
+  explicit_prefix: bool => explicitPrefix = false,
   fs_identifier: FsIdentifier => fsIdentifier,
 
   boot_path: &'a Path => bootPath,
@@ -42,18 +67,23 @@ config! {
   gfx_payload_efi: &'a str => gfxpayloadEfi,
   gfx_payload_bios: &'a str => gfxpayloadBios,
 
-  font: &'a Path => font,
+  font: Fonts<'a> => font,
+  font_size: u32 => fontSize = 16,
   theme: Option<&'a Path> => theme,
   shell: &'a Path => shell,
   path: &'a str => path,
 
   users: Users<'a> => users,
+  hash_plaintext_passwords: bool => hashPlaintextPasswords = false,
 
   use_os_prober: bool => useOSProber,
 
+  sign_config: Option<&'a Path> => signConfig,
+  check_signatures: bool => checkSignatures = false,
+
   can_touch_efi_variables: bool => canTouchEfiVariables,
   efi_install_as_removable: bool => efiInstallAsRemovable,
-  efi_sys_mount_point: &'a Path => efiSysMountPoint,
+  efi_sys_mount_points: EfiSysMountPoints<'a> => efiSysMountPoint,
 
   bootloader_id: &'a str => bootloaderId,
   force_install: bool => forceInstall,
@@ -68,6 +98,21 @@ impl Config<'_> {
 	pub fn save_default(&self) -> bool {
 		self.default_entry == "saved"
 	}
+
+	/// Where the system profile and `system-profiles` are, which is only
+	/// configured when e.g. building an image.
+	pub fn profiles_dir(&self) -> &Path {
+		self.profiles_dir
+			.unwrap_or(Path::new("/nix/var/nix/profiles"))
+	}
+
+	/// Dumps the config as JSON keyed like the XML it was parsed from, for
+	/// debugging. Keys are sorted so the output is stable, and passwords are
+	/// never included.
+	pub fn to_debug_json(&self) -> Result<String> {
+		let value = serde_json::to_value(self)?;
+		Ok(serde_json::to_string_pretty(&value)?)
+	}
 }
 
 pub trait NodeExt<'a, 'input: 'a> {
@@ -75,12 +120,77 @@ pub trait NodeExt<'a, 'input: 'a> {
 }
 pub trait FromNode<'a, 'input: 'a>: Sized {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self>;
+
+	/// The value of a key missing from the config altogether, if that's
+	/// allowed, as it is for optional values.
+	fn absent() -> Option<Self> {
+		None
+	}
 }
 
+/// A platform GRUB can be installed for, e.g. `x86_64-efi` or `arm64-efi`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct GrubTarget<'a>(pub &'a str);
+
+/// One or more console fonts, the first of which is the default.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Fonts<'a>(pub Vec<&'a Path>);
+
+/// One or more EFI system partitions to install GRUB into, e.g. one on each
+/// disk of a mirror.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct EfiSysMountPoints<'a>(pub Vec<&'a Path>);
+
 #[derive(Clone, Debug)]
 pub struct Users<'a>(pub HashMap<&'a str, Password<'a>>);
+impl serde::Serialize for Users<'_> {
+	/// Hashed passwords are redacted, and users with plaintext ones left out
+	/// entirely, so that a dumped config is safe to share.
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_map(self.0.iter().filter_map(|(name, password)| match password {
+			Password::Hashed(_) => Some((name, "<redacted>")),
+			Password::Plain(_) => None,
+		}))
+	}
+}
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// A troubleshooting variant of the default entry, booted with extra kernel
+/// parameters (e.g. `nomodeset`).
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Quirk<'a> {
+	pub title: &'a str,
+	pub params: &'a str,
+}
+
+/// The order in which generations are listed in a profile's submenu. All of
+/// them list the highest/newest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GenerationSort {
+	Generation,
+	KernelVersion,
+	Date,
+}
+
+/// How `background_image` fits the splash image to the screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplashMode {
+	/// Centered at its original size. The default.
+	Normal,
+	/// Scaled to fill the whole screen.
+	Stretch,
+}
+impl SplashMode {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Normal => "normal",
+			Self::Stretch => "stretch",
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Password<'a> {
 	Plain(Cow<'a, str>),
 	Hashed(Cow<'a, str>),
@@ -89,7 +199,15 @@ pub enum Password<'a> {
 //= Implementation =//
 
 macro_rules! config {
-  ($lifetime:lifetime; $($field:ident : $ty:ty => $key:ident),*$(,)?) => {
+  // Keys added since the first release have a default, so configs from older
+  // NixOS modules that lack them still parse.
+  (@attr $attrs:ident, $ty:ty, $key:ident) => {
+    $attrs.attr_to::<$ty>(stringify!($key))?
+  };
+  (@attr $attrs:ident, $ty:ty, $key:ident, $default:expr) => {
+    $attrs.attr_or::<$ty>(stringify!($key), $default)?
+  };
+  ($lifetime:lifetime; $($field:ident : $ty:ty => $key:ident $(= $default:expr)?),*$(,)?) => {
     #[derive(Debug, Clone)]
     pub struct Config<$lifetime> {
       $(
@@ -111,10 +229,23 @@ macro_rules! config {
 	    	let root_attrs = root_attrs.to::<AttrsNode>()?;
 
 		    Ok(Self {$(
-          $field: root_attrs.attr_to::<$ty>(stringify!($key))?
+          $field: config!(@attr root_attrs, $ty, $key $(, $default)?)
         ),*})
 	    }
     }
+
+    impl serde::Serialize for Config<'_> {
+      fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let len = [$(stringify!($key)),*].len();
+        let mut state = serializer.serialize_struct("Config", len)?;
+        $(
+          state.serialize_field(stringify!($key), &self.$field)?;
+        )*
+        state.end()
+      }
+    }
   }
 }
 use config;
@@ -138,12 +269,23 @@ impl<'a, 'input> AttrsNode<'a, 'input> {
 	}
 
 	fn attr_to<T: FromNode<'a, 'input>>(&self, key: &'input str) -> Result<T> {
-		let attr = self.attr(key)?;
+		let attr = match self.attr(key) {
+			Ok(attr) => attr,
+			Err(e) => return T::absent().ok_or(e),
+		};
 		let attr =
 			T::from_node(attr).with_context(|| format!("While trying to read attr `{key}`"))?;
 		Ok(attr)
 	}
 
+	/// Like [`Self::attr_to`], but with `default` for a missing key.
+	fn attr_or<T: FromNode<'a, 'input>>(&self, key: &'input str, default: T) -> Result<T> {
+		match self.attr(key) {
+			Ok(_) => self.attr_to(key),
+			Err(_) => Ok(default),
+		}
+	}
+
 	fn attrs(&self) -> impl Iterator<Item = (&'a str, Node<'a, 'input>)> {
 		self.node
 			.children()
@@ -159,24 +301,32 @@ impl<'a, 'input: 'a> FromNode<'a, 'input> for Users<'a> {
 			.map(|(user, node)| {
 				let fields = node.to::<AttrsNode>()?;
 
-				let hashed_password_file = fields.attr_to::<&Path>("hashedPasswordFile");
-				let hashed_password = fields.attr_to::<&str>("hashedPassword");
-				let password_file = fields.attr_to::<&Path>("passwordFile");
-				let password = fields.attr_to::<&str>("password");
+				// Each of these may be missing or null, but anything else that
+				// fails to parse is a mistake worth reporting.
+				let hashed_password_file = fields.attr_to::<Option<&Path>>("hashedPasswordFile")?;
+				let hashed_password = fields.attr_to::<Option<&str>>("hashedPassword")?;
+				let password_file = fields.attr_to::<Option<&Path>>("passwordFile")?;
+				let password = fields.attr_to::<Option<&str>>("password")?;
 
-				let password = if let Ok(f) = hashed_password_file {
+				let password = if let Some(f) = hashed_password_file {
 					let f = std::fs::read_to_string(f).with_context(|| {
-						format!("While trying to read hashed password file for user {user}")
+						format!(
+							"While trying to read hashed password file {} for user {user}",
+							f.display()
+						)
 					})?;
 					Password::Hashed(f.into())
-				} else if let Ok(f) = hashed_password {
+				} else if let Some(f) = hashed_password {
 					Password::Hashed(f.into())
-				} else if let Ok(f) = password_file {
+				} else if let Some(f) = password_file {
 					let f = std::fs::read_to_string(f).with_context(|| {
-						format!("While trying to read plain password file for user {user}")
+						format!(
+							"While trying to read plain password file {} for user {user}",
+							f.display()
+						)
 					})?;
 					Password::Plain(f.into())
-				} else if let Ok(f) = password {
+				} else if let Some(f) = password {
 					Password::Plain(f.into())
 				} else {
 					bail!("Password not found for user {user}!")
@@ -196,6 +346,114 @@ impl<'a, 'input: 'a> FromNode<'a, 'input> for Users<'a> {
 	}
 }
 
+impl<'a, 'input: 'a> FromNode<'a, 'input> for Option<GrubTarget<'a>> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		/// Every `<cpu>-<platform>` combination GRUB supports.
+		const KNOWN_TARGETS: &[&str] = &[
+			"arm-coreboot",
+			"arm-efi",
+			"arm-uboot",
+			"arm64-efi",
+			"i386-coreboot",
+			"i386-efi",
+			"i386-ieee1275",
+			"i386-multiboot",
+			"i386-pc",
+			"i386-qemu",
+			"i386-xen",
+			"i386-xen_pvh",
+			"ia64-efi",
+			"loongarch64-efi",
+			"mips-arc",
+			"mips-qemu_mips",
+			"mipsel-arc",
+			"mipsel-loongson",
+			"mipsel-qemu_mips",
+			"powerpc-ieee1275",
+			"riscv32-efi",
+			"riscv64-efi",
+			"sparc64-ieee1275",
+			"x86_64-efi",
+			"x86_64-xen",
+		];
+
+		let Some(target) = node.to::<Option<&str>>()? else {
+			return Ok(None);
+		};
+		if !KNOWN_TARGETS.contains(&target) {
+			bail!(
+				"Unknown GRUB target `{target}`: expected one of {}",
+				KNOWN_TARGETS.join(", ")
+			);
+		}
+		Ok(Some(GrubTarget(target)))
+	}
+
+	fn absent() -> Option<Self> {
+		Some(None)
+	}
+}
+
+impl<'a, 'input: 'a> FromNode<'a, 'input> for Fonts<'a> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		// Historically only a single font was supported, so accept both forms.
+		if node.tag_name().name() == "list" {
+			node.to::<Vec<&Path>>().map(Fonts)
+		} else {
+			node.to::<&Path>().map(|f| Fonts(vec![f]))
+		}
+	}
+}
+
+impl<'a, 'input: 'a> FromNode<'a, 'input> for EfiSysMountPoints<'a> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		// Most systems only have one ESP, so accept both forms.
+		let mount_points = if node.tag_name().name() == "list" {
+			node.to::<Vec<&Path>>()?
+		} else {
+			vec![node.to::<&Path>()?]
+		};
+		if mount_points.is_empty() {
+			bail!("efiSysMountPoint must have at least one mount point");
+		}
+		Ok(Self(mount_points))
+	}
+}
+
+impl<'a, 'input: 'a> FromNode<'a, 'input> for Quirk<'a> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		let fields = node.to::<AttrsNode>()?;
+
+		Ok(Self {
+			title: fields.attr_to("title")?,
+			params: fields.attr_to("params")?,
+		})
+	}
+}
+
+impl<'a, 'input: 'a> FromNode<'a, 'input> for GenerationSort {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		match node.to::<&str>()? {
+			"generation" => Ok(Self::Generation),
+			"kernel-version" => Ok(Self::KernelVersion),
+			"date" => Ok(Self::Date),
+			s => Err(eyre!("Invalid generation sort order: {s}")),
+		}
+	}
+}
+
+impl<'a, 'input: 'a> FromNode<'a, 'input> for SplashMode {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		match node.to::<&str>()? {
+			"" | "normal" => Ok(Self::Normal),
+			"stretch" => Ok(Self::Stretch),
+			s => Err(eyre!(
+				"Invalid splash mode: {s} (`splashMode` must be \"normal\" or \"stretch\")"
+			)),
+		}
+	}
+}
+
 impl<'a, 'input: 'a> FromNode<'a, 'input> for FsIdentifier {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
 		match node.to::<&str>()? {
@@ -214,9 +472,16 @@ impl<'a, 'input: 'a> FromNode<'a, 'input> for &'a str {
 }
 impl<'a, 'input: 'a> FromNode<'a, 'input> for Option<&'a str> {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		if node.tag_name().name() == "null" {
+			return Ok(None);
+		}
 		let s = node.to::<&str>()?;
 		Ok(if s.is_empty() { None } else { Some(s) })
 	}
+
+	fn absent() -> Option<Self> {
+		Some(None)
+	}
 }
 impl<'a, 'input: 'a> FromNode<'a, 'input> for &'a Path {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
@@ -225,12 +490,11 @@ impl<'a, 'input: 'a> FromNode<'a, 'input> for &'a Path {
 }
 impl<'a, 'input: 'a> FromNode<'a, 'input> for Option<&'a Path> {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
-		let s = node.to::<&str>()?;
-		Ok(if s.is_empty() {
-			None
-		} else {
-			Some(Path::new(s))
-		})
+		Ok(node.to::<Option<&str>>()?.map(Path::new))
+	}
+
+	fn absent() -> Option<Self> {
+		Some(None)
 	}
 }
 impl<'a, 'input: 'a> FromNode<'a, 'input> for bool {
@@ -238,6 +502,19 @@ impl<'a, 'input: 'a> FromNode<'a, 'input> for bool {
 		check_tag_name(node, "bool", |node| Ok(value(node)? == "true"))
 	}
 }
+impl<'a, 'input: 'a> FromNode<'a, 'input> for Option<bool> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		if node.tag_name().name() == "null" {
+			Ok(None)
+		} else {
+			node.to::<bool>().map(Some)
+		}
+	}
+
+	fn absent() -> Option<Self> {
+		Some(None)
+	}
+}
 impl<'a, 'input: 'a, T: FromNode<'a, 'input>> FromNode<'a, 'input> for Vec<T> {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
 		check_tag_name(node, "list", |node| {
@@ -248,6 +525,14 @@ impl<'a, 'input: 'a, T: FromNode<'a, 'input>> FromNode<'a, 'input> for Vec<T> {
 		})
 	}
 }
+impl<'a, 'input: 'a, T: FromNode<'a, 'input>> FromNode<'a, 'input> for HashMap<&'a str, T> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		node.to::<AttrsNode>()?
+			.attrs()
+			.map(|(name, node)| Ok((name, node.to::<T>()?)))
+			.collect()
+	}
+}
 impl<'a, 'input: 'a> FromNode<'a, 'input> for AttrsNode<'a, 'input> {
 	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
 		check_tag_name(node, "attrs", |node| Ok(AttrsNode { node }))
@@ -264,10 +549,48 @@ macro_rules! int_impl {
 		      })
       	}
       }
+      impl<'a, 'input: 'a> FromNode<'a, 'input> for Option<$ty> {
+      	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		      if node.tag_name().name() == "null" {
+		      	Ok(None)
+		      } else {
+		      	node.to::<$ty>().map(Some)
+		      }
+      	}
+
+      	fn absent() -> Option<Self> {
+      		Some(None)
+      	}
+      }
     )*
   }
 }
-int_impl!(u8 u16 u32 u64 u128 usize);
+int_impl!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize);
+
+impl<'a, 'input: 'a> FromNode<'a, 'input> for f64 {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		// Whole numbers are ints in Nix, even where a float is meant.
+		match node.tag_name().name() {
+			"float" | "int" => value(node)?
+				.parse()
+				.map_err(|e| eyre!("Invalid float: {e}")),
+			found => bail!("Found unexpected tag {found}, expecting float or int"),
+		}
+	}
+}
+impl<'a, 'input: 'a> FromNode<'a, 'input> for Option<f64> {
+	fn from_node(node: Node<'a, 'input>) -> Result<Self> {
+		if node.tag_name().name() == "null" {
+			Ok(None)
+		} else {
+			node.to::<f64>().map(Some)
+		}
+	}
+
+	fn absent() -> Option<Self> {
+		Some(None)
+	}
+}
 
 fn check_tag_name<'a, 'input, F, R>(
 	node: Node<'a, 'input>,
@@ -290,3 +613,39 @@ fn value<'a, 'input: 'a>(node: Node<'a, 'input>) -> Result<&'a str> {
 	node.attribute("value")
 		.ok_or(eyre!("`value` attribute not found"))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_quirk_entries() {
+		let doc = Document::parse(
+			r#"<list><attrs>
+				<attr name="title"><string value="Safe graphics" /></attr>
+				<attr name="params"><string value="nomodeset" /></attr>
+			</attrs></list>"#,
+		)
+		.unwrap();
+
+		let quirks = doc.root_element().to::<Vec<Quirk>>().unwrap();
+
+		assert_eq!(quirks.len(), 1);
+		assert_eq!(quirks[0].title, "Safe graphics");
+		assert_eq!(quirks[0].params, "nomodeset");
+	}
+
+	#[test]
+	fn parses_splash_modes() {
+		let parse = |mode: &str| {
+			let xml = format!("<string value=\"{mode}\" />");
+			let doc = Document::parse(&xml).unwrap();
+			doc.root_element().to::<SplashMode>()
+		};
+
+		assert_eq!(parse("").unwrap(), SplashMode::Normal);
+		assert_eq!(parse("normal").unwrap(), SplashMode::Normal);
+		assert_eq!(parse("stretch").unwrap(), SplashMode::Stretch);
+		assert!(parse("tile").is_err());
+	}
+}
@@ -17,54 +17,77 @@ pub struct Grub {
 	pub search: String,
 }
 impl Grub {
-	pub fn new(dir: &Path, config: &Config) -> Result<Self> {
+	/// Locates `dir`, which is under `image_root` if one is given. GRUB sees
+	/// that as the root of its filesystem, even if it's only a staging
+	/// directory rather than where the filesystem is mounted.
+	pub fn new(dir: &Path, image_root: Option<&Path>, config: &Config) -> Result<Self> {
 		let fs = Fs::new(dir)?;
 
-		let path = dir.strip_prefix(&fs.mount)?.to_owned();
+		let root = match image_root {
+			Some(root) if root.starts_with(&fs.mount) => root,
+			_ => &fs.mount,
+		};
+		let path = dir.strip_prefix(root)?.to_owned();
 
-		let (search, path) = if fs.fs_type == "zfs" {
+		if FsKind::detect(&fs.fs_type) == FsKind::Zfs {
 			// ZFS is completely separate logic as zpools are always identified by a label
 			// or custom UUID
-			let mut new_path = PathBuf::from("/");
-
-			let mut components = fs.device.components();
-			let label = if let Some(label) = components.next() {
-				new_path.push(components.as_path());
-
-				Path::new(label.as_os_str())
-			} else {
-				&fs.device
-			};
+			let (label, new_path) = zfs_path(&fs.device, &path);
 
-			new_path.push("@");
-			new_path.push(path);
-
-			(format!("--label {}", label.display()), new_path)
+			Self::with_identifier(FsIdentifier::Label, &label.to_string_lossy(), &new_path)
 		} else {
-			let search = config.fs_identifier.to_search(&fs)?;
+			let id = match config.fs_identifier {
+				FsIdentifier::Uuid => FsIdentifier::query_blkid(&fs, "UUID")?,
+				FsIdentifier::Label => FsIdentifier::query_blkid(&fs, "LABEL")?,
+				FsIdentifier::Provided => fs.device.to_string_lossy().into_owned(),
+			};
 			// BTRFS is a special case in that we need to fix the referenced path based on
 			// subvolumes
 			let path = Self::alter_path_for_btrfs(&fs, path)?;
-			(search, path)
-		};
 
-		if !search.is_empty() {
-			static DRIVE_ID: AtomicUsize = AtomicUsize::new(1);
-			let drive_id = DRIVE_ID.fetch_add(1, SeqCst);
-			let mut drive = PathBuf::from(format!("($drive{drive_id})"));
-			drive.push(path);
-
-			Ok(Grub {
-				path: drive,
-				search: format!("search --set=drive{drive_id} {search}"),
+			Self::with_identifier(config.fs_identifier, &id, &path).with_context(|| {
+				format!("Cannot derive a search command for {}", fs.mount.display())
 			})
-		} else {
-			Ok(Grub { path, search })
 		}
 	}
 
+	/// Builds the path GRUB can reach `path` by, on the filesystem identified
+	/// by `id`, along with the `search` line locating that filesystem. `id` is
+	/// a UUID or label, or the device path for [`FsIdentifier::Provided`].
+	pub fn with_identifier(identifier: FsIdentifier, id: &str, path: &Path) -> Result<Self> {
+		let search = match identifier {
+			FsIdentifier::Uuid | FsIdentifier::Label => format!("{} {id}", identifier.to_flag()),
+			FsIdentifier::Provided => {
+				if id.is_empty() {
+					bail!("With `fsIdentifier = \"provided\"`, a device is needed")
+				}
+				match FsIdentifier::provided_search(Path::new(id)) {
+					Some(search) => search,
+					// Any other device can't be searched for, so paths stay relative to
+					// whatever `$root` GRUB booted from.
+					None => {
+						return Ok(Grub {
+							path: path.to_owned(),
+							search: String::new(),
+						})
+					}
+				}
+			}
+		};
+
+		static DRIVE_ID: AtomicUsize = AtomicUsize::new(1);
+		let drive_id = DRIVE_ID.fetch_add(1, SeqCst);
+		let mut drive = PathBuf::from(format!("($drive{drive_id})"));
+		drive.push(path);
+
+		Ok(Grub {
+			path: drive,
+			search: format!("search --set=drive{drive_id} {search}"),
+		})
+	}
+
 	fn alter_path_for_btrfs(fs: &Fs, path: PathBuf) -> Result<PathBuf> {
-		if fs.fs_type != "btrfs" {
+		if FsKind::detect(&fs.fs_type) != FsKind::Btrfs {
 			return Ok(path);
 		}
 
@@ -165,6 +188,46 @@ impl Grub {
 	}
 }
 
+/// Filesystems whose paths as seen by GRUB differ from how they're mounted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsKind {
+	/// Paths are relative to the subvolume's path from the top-level volume.
+	Btrfs,
+	/// Paths are relative to the pool and go through the dataset's `@`.
+	Zfs,
+	Other,
+}
+impl FsKind {
+	/// Tells the kind of a filesystem from its type in /proc/self/mountinfo.
+	pub fn detect(fs_type: &str) -> Self {
+		match fs_type {
+			"btrfs" => Self::Btrfs,
+			"zfs" => Self::Zfs,
+			_ => Self::Other,
+		}
+	}
+}
+
+/// Splits a ZFS dataset such as `rpool/root` into the pool's label, and the
+/// path GRUB reaches `path` within the dataset by, e.g. `/root/@/boot`.
+pub fn zfs_path(dataset: &Path, path: &Path) -> (PathBuf, PathBuf) {
+	let mut new_path = PathBuf::from("/");
+
+	let mut components = dataset.components();
+	let label = if let Some(label) = components.next() {
+		new_path.push(components.as_path());
+
+		PathBuf::from(label.as_os_str())
+	} else {
+		dataset.to_owned()
+	};
+
+	new_path.push("@");
+	new_path.push(path);
+
+	(label, new_path)
+}
+
 #[derive(Clone, Debug, Default)]
 struct Fs {
 	device: PathBuf,
@@ -234,10 +297,20 @@ impl Fs {
 	}
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How GRUB should locate the filesystems holding /boot and /nix/store, i.e.
+/// what goes into the `search --set=driveN ...` line emitted for
+/// `grub_boot.search` and `grub_store.search`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FsIdentifier {
+	/// `search --fs-uuid <uuid>`, with the UUID queried from blkid.
 	Uuid,
+	/// `search --label <label>`, with the label queried from blkid.
 	Label,
+	/// Derived from the mounted device path itself: `/dev/disk/by-uuid/<uuid>`
+	/// becomes `search --fs-uuid <uuid>`, and `/dev/disk/by-label/<label>`
+	/// becomes `search --label <label>`. Other devices get no `search` at all,
+	/// leaving paths relative to `$root`.
 	Provided,
 }
 impl FsIdentifier {
@@ -249,14 +322,6 @@ impl FsIdentifier {
 		}
 	}
 
-	fn to_search(self, fs: &Fs) -> Result<String> {
-		match self {
-			Self::Uuid => self.query_blkid(fs, "UUID"),
-			Self::Label => self.query_blkid(fs, "LABEL"),
-			Self::Provided => Ok(Self::provided_search(&fs.device).unwrap_or_default()),
-		}
-	}
-
 	fn provided_search(device: &Path) -> Option<String> {
 		// If the provided dev is identifying the partition using a label or uuid,
 		// we should get the label / uuid and do a proper search
@@ -289,7 +354,8 @@ impl FsIdentifier {
 		))
 	}
 
-	fn query_blkid(&self, fs: &Fs, key: &str) -> Result<String> {
+	/// Queries blkid for the UUID or label of a filesystem.
+	fn query_blkid(fs: &Fs, key: &str) -> Result<String> {
 		// Based on the type pull in the identifier from the system
 
 		let Output {
@@ -317,9 +383,84 @@ impl FsIdentifier {
 				continue;
 			};
 			if key == k {
-				return Ok(format!("{} {v}", self.to_flag()));
+				return Ok(v.to_owned());
 			}
 		}
 		bail!("Couldn't find a {key} for {}", fs.device.display());
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Checks `grub` searches with `expected`, and that its path is `path` on
+	/// the drive found by it. Drive numbers are shared across tests, so
+	/// they're only checked for matching each other.
+	fn assert_search(grub: &Grub, expected: &str, path: &str) {
+		let (drive, search) = grub
+			.search
+			.strip_prefix("search --set=")
+			.and_then(|s| s.split_once(' '))
+			.unwrap();
+		assert_eq!(search, expected);
+		assert_eq!(grub.path, Path::new(&format!("(${drive})")).join(path));
+	}
+
+	#[test]
+	fn searches_by_uuid() {
+		let grub = Grub::with_identifier(FsIdentifier::Uuid, "1234-ABCD", Path::new("boot"));
+		assert_search(&grub.unwrap(), "--fs-uuid 1234-ABCD", "boot");
+	}
+
+	#[test]
+	fn searches_by_label() {
+		let grub = Grub::with_identifier(FsIdentifier::Label, "nixos", Path::new("boot"));
+		assert_search(&grub.unwrap(), "--label nixos", "boot");
+	}
+
+	#[test]
+	fn searches_by_provided_uuid() {
+		let grub = Grub::with_identifier(
+			FsIdentifier::Provided,
+			"/dev/disk/by-uuid/1234-ABCD",
+			Path::new("boot"),
+		);
+		assert_search(&grub.unwrap(), "--fs-uuid 1234-ABCD", "boot");
+	}
+
+	#[test]
+	fn searches_by_provided_label() {
+		let grub = Grub::with_identifier(
+			FsIdentifier::Provided,
+			"/dev/disk/by-label/nixos",
+			Path::new("boot"),
+		);
+		assert_search(&grub.unwrap(), "--label nixos", "boot");
+	}
+
+	#[test]
+	fn provided_devices_without_an_id_are_not_searched() {
+		let grub =
+			Grub::with_identifier(FsIdentifier::Provided, "/dev/sda1", Path::new("boot")).unwrap();
+		assert_eq!(grub.search, "");
+		assert_eq!(grub.path, Path::new("boot"));
+	}
+
+	#[test]
+	fn provided_needs_a_device() {
+		assert!(Grub::with_identifier(FsIdentifier::Provided, "", Path::new("boot")).is_err());
+	}
+
+	#[test]
+	fn zfs_paths() {
+		assert_eq!(
+			zfs_path(Path::new("rpool/root"), Path::new("boot")),
+			(PathBuf::from("rpool"), PathBuf::from("/root/@/boot"))
+		);
+		assert_eq!(
+			zfs_path(Path::new("rpool"), Path::new("boot")),
+			(PathBuf::from("rpool"), PathBuf::from("/@/boot"))
+		);
+	}
+}
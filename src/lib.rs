@@ -0,0 +1,45 @@
+//! Generates grub.cfg for a NixOS system and installs GRUB, so that it can be
+//! driven from other programs (e.g. an installer) without spawning
+//! `install-grub`, which is itself a thin wrapper around [`install`].
+
+pub mod args;
+pub mod builder;
+pub mod config;
+pub mod grub;
+
+use eyre::Result;
+
+pub use crate::{args::Args as InstallOptions, builder::Builder, config::Config};
+
+/// Generates grub.cfg from `config`, without writing anything to disk.
+///
+/// `opts` is needed for the default system configuration and such; it's
+/// always treated as a dry run.
+pub fn generate_config(config: &Config, opts: &InstallOptions) -> Result<String> {
+	let opts = InstallOptions {
+		dry_run: true,
+		..opts.clone()
+	};
+	let mut builder = Builder::new(config.clone(), &opts)?;
+
+	builder.users()?.default_entry()?.appearance()?.entries()?;
+
+	Ok(builder.render().to_owned())
+}
+
+/// Generates grub.cfg from `config` and installs GRUB, skipping the latter
+/// when nothing changed since the last run unless forced to.
+pub fn install(config: &Config, opts: &InstallOptions) -> Result<()> {
+	let mut builder = Builder::new(config.clone(), opts)?;
+
+	eprintln!("updating GRUB 2 menu...");
+
+	builder
+		.users()?
+		.default_entry()?
+		.appearance()?
+		.entries()?
+		.install()?;
+
+	Ok(())
+}
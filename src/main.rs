@@ -1,49 +1,52 @@
-mod builder;
-mod config;
-mod grub;
-
-use std::{os::linux::fs::MetadataExt, path::Path};
-
 use eyre::{bail, Result};
+use install_grub::{
+	args::{Args, Subcommand},
+	builder, Builder, Config,
+};
 use roxmltree::Document;
 
-use crate::{builder::Builder, config::Config};
-
 fn main() -> Result<()> {
 	color_eyre::install()?;
 
-	let mut args = std::env::args();
-	let Some(config_file) = args.nth(1) else {
-		bail!("Config file not given: expected it to be the first argument")
-	};
-	let Some(default_config) = args.next() else {
-		bail!("Default config not given: expected it to be the second argument")
-	};
-
-	// For debugging purposes
+	let args = Args::parse()?;
 
-	let document_file = std::fs::read_to_string(config_file)?;
+	let document_file = std::fs::read_to_string(&args.config_file)?;
 	let document = Document::parse(&document_file)?;
 
-	let mut config = Config::new(&document)?;
-
-	// Discover whether the bootPath is on the same filesystem as / and
-	// /nix/store.  If not, then all kernels and initrds must be copied to
-	// the bootPath.
-	if config.boot_path.metadata()?.st_dev() != Path::new("/nix/store").metadata()?.st_dev() {
-		config.copy_kernels = true;
+	let config = Config::new(&document)?;
+
+	match args.command {
+		Subcommand::PrintConfigPath => {
+			println!("{}", builder::grub_cfg_path(&config, &args).display());
+			return Ok(());
+		}
+		Subcommand::PrintStatePath => {
+			println!("{}", builder::state_path(&config, &args).display());
+			return Ok(());
+		}
+		Subcommand::DumpConfig => {
+			println!("{}", config.to_debug_json()?);
+			return Ok(());
+		}
+		_ => {}
 	}
 
-	eprintln!("updating GRUB 2 menu...");
+	if let Subcommand::Render { phase } = args.command {
+		let Some(phase) = phase else {
+			bail!("`render` requires a phase: `render --phase <phase>`")
+		};
+		print!("{}", Builder::new(config, &args)?.render_phase(phase)?);
+		return Ok(());
+	}
 
-	std::env::set_var("PATH", config.path);
+	if args.command == Subcommand::CompareState {
+		return Builder::new(config, &args)?.compare_state();
+	}
 
-	Builder::new(config, Path::new(&default_config))?
-		.users()?
-		.default_entry()?
-		.appearance()?
-		.entries()?
-		.install()?;
+	if args.command == Subcommand::ListGenerations {
+		print!("{}", Builder::new(config, &args)?.list_generations()?);
+		return Ok(());
+	}
 
-	Ok(())
+	install_grub::install(&config, &args)
 }
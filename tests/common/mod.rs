@@ -0,0 +1,281 @@
+//! A fake NixOS system in a temporary directory: a store with a single
+//! generation, the profile pointing at it, an empty /boot and ESP, and a GRUB
+//! package and gpg that only record how they were run.
+
+// Each test binary only uses some of it.
+#![allow(dead_code)]
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	os::unix::fs::{symlink, PermissionsExt},
+	path::{Path, PathBuf},
+};
+
+use install_grub::{Builder, Config, InstallOptions};
+use nix::sys::{
+	stat::{utimensat, UtimensatFlags},
+	time::TimeSpec,
+};
+use roxmltree::Document;
+use tempfile::TempDir;
+
+pub struct Fixture {
+	pub dir: TempDir,
+	attrs: BTreeMap<&'static str, String>,
+}
+impl Fixture {
+	pub fn new() -> Self {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path();
+
+		let kernel = root.join("store/aaaa-linux-6.6.1");
+		fs::create_dir_all(kernel.join("lib/modules/6.6.1")).unwrap();
+		fs::write(kernel.join("bzImage"), "kernel").unwrap();
+
+		let initrd = root.join("store/bbbb-initrd-linux-6.6.1");
+		fs::create_dir_all(&initrd).unwrap();
+		fs::write(initrd.join("initrd"), "initrd").unwrap();
+
+		let system = root.join("store/cccc-nixos-system-test-24.05");
+		fs::create_dir_all(system.join("specialisation")).unwrap();
+		symlink(kernel.join("bzImage"), system.join("kernel")).unwrap();
+		symlink(initrd.join("initrd"), system.join("initrd")).unwrap();
+		fs::write(system.join("init"), "").unwrap();
+		fs::write(system.join("kernel-params"), "loglevel=4\n").unwrap();
+		fs::write(system.join("nixos-version"), "24.05").unwrap();
+
+		fs::create_dir_all(root.join("profiles")).unwrap();
+		symlink(&system, root.join("profiles/system-1-link")).unwrap();
+		symlink("system-1-link", root.join("profiles/system")).unwrap();
+
+		fs::create_dir_all(root.join("boot")).unwrap();
+		fs::create_dir_all(root.join("efi")).unwrap();
+
+		let grub = root.join("store/dddd-grub-2.12");
+		for tool in [
+			"sbin/grub-install",
+			"bin/grub-script-check",
+			"etc/grub.d/30_os-prober",
+		] {
+			fake_tool(root, &grub.join(tool), "");
+		}
+		// Writes the file given by `--output`, like `gpg --detach-sign` does.
+		fake_tool(
+			root,
+			&root.join("bin/gpg"),
+			"while [ $# -gt 0 ]; do\n  [ \"$1\" = --output ] && echo signature > \"$2\"\n  \
+			 shift\ndone\n",
+		);
+		let search_path = format!(
+			"{}:{}",
+			root.join("bin").display(),
+			std::env::var("PATH").unwrap_or_default()
+		);
+
+		let path = |p: PathBuf| string(&p.to_string_lossy());
+		let mut attrs = BTreeMap::new();
+		for (key, value) in [
+			("distroName", string("NixOS")),
+			("grub", string("")),
+			("grubTarget", string("")),
+			("grubEfi", path(grub)),
+			("grubTargetEfi", string("x86_64-efi")),
+			("requireExplicitTarget", boolean(false)),
+			("extraConfig", string("")),
+			("extraPrepareConfig", string("")),
+			("extraEntries", string("")),
+			("extraEntriesBeforeNixOS", boolean(false)),
+			("quirkEntries", list(&[])),
+			("allowCustomCfg", boolean(false)),
+			("splashMode", string("normal")),
+			("entryOptions", string("--class nixos --unrestricted")),
+			("subEntryOptions", string("--class nixos")),
+			("dedupeKernelParams", boolean(false)),
+			("profilesDir", path(root.join("profiles"))),
+			("configurationLimit", int(100)),
+			("specialisationLimit", int(0)),
+			(
+				"profileConfigurationLimits",
+				String::from("<attrs></attrs>"),
+			),
+			("generationSort", string("generation")),
+			("excludeGenerations", list(&[])),
+			("groupGenerationsByMonth", boolean(false)),
+			("copyKernels", boolean(false)),
+			("preferHardlinks", boolean(false)),
+			("earlyInitrds", list(&[])),
+			("groupCurrentSpecialisations", boolean(false)),
+			("timeout", int(5)),
+			("timeoutStyle", string("menu")),
+			("default", string("0")),
+			("explicitPrefix", boolean(false)),
+			("fsIdentifier", string("provided")),
+			("bootPath", path(root.join("boot"))),
+			("storePath", path(root.join("store"))),
+			("gfxmodeEfi", string("auto")),
+			("gfxmodeBios", string("1024x768")),
+			("gfxpayloadEfi", string("keep")),
+			("gfxpayloadBios", string("text")),
+			("font", list(&[])),
+			("fontSize", int(16)),
+			("shell", string("/bin/sh")),
+			("path", string(&search_path)),
+			("users", String::from("<attrs></attrs>")),
+			("hashPlaintextPasswords", boolean(false)),
+			("useOSProber", boolean(false)),
+			("checkSignatures", boolean(false)),
+			("canTouchEfiVariables", boolean(false)),
+			("efiInstallAsRemovable", boolean(false)),
+			("efiSysMountPoint", path(root.join("efi"))),
+			("bootloaderId", string("NixOS-boot")),
+			("forceInstall", boolean(false)),
+			("devices", list(&[string("nodev")])),
+			("extraGrubInstallArgs", list(&[])),
+			("fullName", string("grub-2.12")),
+			("fullVersion", string("2.12")),
+		] {
+			attrs.insert(key, value);
+		}
+
+		Self { dir, attrs }
+	}
+
+	/// Adds another generation of the system profile, made at `time` (in
+	/// seconds since the epoch), booting the same system as the first.
+	pub fn add_generation(&self, gen: u32, time: i64) {
+		let link = self.root().join(format!("profiles/system-{gen}-link"));
+		symlink(
+			fs::read_link(self.root().join("profiles/system-1-link")).unwrap(),
+			&link,
+		)
+		.unwrap();
+		let time = TimeSpec::new(time, 0);
+		utimensat(None, &link, &time, &time, UtimensatFlags::NoFollowSymlink).unwrap();
+	}
+
+	/// Gives the system a specialisation, which boots the same as the system
+	/// itself.
+	pub fn add_specialisation(&self, name: &str) {
+		let system = fs::read_link(self.root().join("profiles/system-1-link")).unwrap();
+		symlink(&system, system.join("specialisation").join(name)).unwrap();
+	}
+
+	pub fn root(&self) -> &Path {
+		self.dir.path()
+	}
+
+	/// Overrides a key of the config, given as a Nix XML value, e.g. from
+	/// [`string`].
+	pub fn set(&mut self, key: &'static str, value: String) {
+		self.attrs.insert(key, value);
+	}
+
+	/// Leaves a key out of the config, as older versions of the module do.
+	pub fn unset(&mut self, key: &'static str) {
+		self.attrs.remove(key);
+	}
+
+	/// The config as `nix-instantiate --xml` would print it.
+	pub fn xml(&self) -> String {
+		let attrs = self
+			.attrs
+			.iter()
+			.map(|(key, value)| format!("<attr name=\"{key}\">{value}</attr>"))
+			.collect::<String>();
+		format!("<?xml version='1.0' encoding='utf-8'?>\n<expr><attrs>{attrs}</attrs></expr>")
+	}
+
+	/// Parses the config and runs `f` with it, as it borrows from the XML.
+	pub fn with_config<T>(&self, f: impl FnOnce(Config) -> T) -> T {
+		let xml = self.xml();
+		let doc = Document::parse(&xml).unwrap();
+		f(Config::new(&doc).unwrap())
+	}
+
+	/// Generates grub.cfg without writing anything, like
+	/// [`install_grub::generate_config`].
+	pub fn generate(&self, opts: &InstallOptions) -> eyre::Result<String> {
+		self.with_config(|config| install_grub::generate_config(&config, opts))
+	}
+
+	/// Generates grub.cfg and installs GRUB, like `install-grub` does.
+	pub fn install(&self, opts: &InstallOptions) -> eyre::Result<()> {
+		self.with_config(|config| install_grub::install(&config, opts))
+	}
+
+	/// Lists the generations and why any are left off the menu, like
+	/// `install-grub list-generations`.
+	pub fn list_generations(&self, opts: &InstallOptions) -> eyre::Result<String> {
+		self.with_config(|config| Builder::new(config, opts)?.list_generations())
+	}
+
+	/// Has grub.cfg signed, with a key only the fake gpg knows how to use.
+	pub fn sign_config(&mut self) {
+		let key = self.root().join("key");
+		self.set("signConfig", string(&key.to_string_lossy()));
+	}
+
+	pub fn options(&self) -> InstallOptions {
+		InstallOptions {
+			default_config: self.root().join("profiles/system"),
+			..Default::default()
+		}
+	}
+
+	pub fn grub_cfg(&self) -> String {
+		fs::read_to_string(self.root().join("boot/grub/grub.cfg")).unwrap()
+	}
+
+	/// Every command the fake GRUB tools were run with, in order.
+	pub fn commands(&self) -> Vec<String> {
+		fs::read_to_string(self.root().join("commands"))
+			.unwrap_or_default()
+			.lines()
+			.map(str::to_owned)
+			.collect()
+	}
+
+	/// Makes the fake tool of that name fail from now on.
+	pub fn fail(&self, tool: &str) {
+		fs::write(self.root().join(format!("fail-{tool}")), "").unwrap();
+	}
+}
+
+/// Writes a script that records how it was run to `commands`, and fails if
+/// `fail-<name>` exists, before running `body`.
+fn fake_tool(root: &Path, path: &Path, body: &str) {
+	fs::create_dir_all(path.parent().unwrap()).unwrap();
+	let name = path.file_name().unwrap().to_str().unwrap();
+	fs::write(
+		path,
+		format!(
+			"#!/bin/sh\necho \"{name} $*\" >> {log}\n[ -e {fail} ] && exit 1\n{body}exit 0\n",
+			log = root.join("commands").display(),
+			fail = root.join(format!("fail-{name}")).display(),
+		),
+	)
+	.unwrap();
+	fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+pub fn string(s: &str) -> String {
+	let s = s
+		.replace('&', "&amp;")
+		.replace('"', "&quot;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;");
+	format!("<string value=\"{s}\" />")
+}
+
+pub fn boolean(b: bool) -> String {
+	format!("<bool value=\"{b}\" />")
+}
+
+pub fn int(i: i64) -> String {
+	format!("<int value=\"{i}\" />")
+}
+
+pub fn list(items: &[String]) -> String {
+	format!("<list>{}</list>", items.concat())
+}
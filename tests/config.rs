@@ -0,0 +1,55 @@
+mod common;
+
+use std::path::Path;
+
+use common::Fixture;
+use install_grub::{
+	config::{GenerationSort, SplashMode},
+	Config,
+};
+use roxmltree::Document;
+
+#[test]
+fn profiles_dir_defaults_to_nix_profiles() {
+	let mut fx = Fixture::new();
+	fx.unset("profilesDir");
+
+	fx.with_config(|config| {
+		assert_eq!(config.profiles_dir(), Path::new("/nix/var/nix/profiles"));
+	});
+}
+
+#[test]
+fn specialisation_limit_is_optional() {
+	let mut fx = Fixture::new();
+	fx.unset("specialisationLimit");
+
+	fx.with_config(|config| assert_eq!(config.specialisation_limit, None));
+}
+
+#[test]
+fn require_explicit_target_is_optional() {
+	let mut fx = Fixture::new();
+	fx.unset("requireExplicitTarget");
+
+	fx.with_config(|config| assert_eq!(config.require_explicit_target, None));
+}
+
+#[test]
+fn parses_configs_from_before_any_new_keys() {
+	let xml = include_str!("fixtures/baseline-config.xml");
+	let doc = Document::parse(xml).unwrap();
+
+	let config = Config::new(&doc).unwrap();
+
+	assert_eq!(config.distro_name, "NixOS");
+	assert!(config.quirk_entries.is_empty());
+	assert_eq!(config.splash_mode, SplashMode::Normal);
+	assert_eq!(config.generation_sort, GenerationSort::Generation);
+	assert!(config.profile_configuration_limits.is_empty());
+	assert_eq!(config.font_size, 16);
+	assert!(!config.explicit_prefix);
+	assert!(!config.hash_plaintext_passwords);
+	assert_eq!(config.sign_config, None);
+	assert!(!config.check_signatures);
+}
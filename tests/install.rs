@@ -0,0 +1,218 @@
+mod common;
+
+use std::{
+	fs,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+};
+
+use common::{boolean, int, string, Fixture};
+use install_grub::InstallOptions;
+
+#[test]
+fn signs_grub_cfg() {
+	let mut fx = Fixture::new();
+	fx.sign_config();
+
+	fx.install(&fx.options()).unwrap();
+
+	assert!(fx.root().join("boot/grub/grub.cfg.sig").exists());
+	assert!(fx
+		.commands()
+		.iter()
+		.any(|c| c.starts_with("gpg") && c.ends_with("boot/grub/grub.cfg")));
+}
+
+#[test]
+fn signs_copied_kernels() {
+	let mut fx = Fixture::new();
+	fx.sign_config();
+	fx.set("checkSignatures", boolean(true));
+	fx.set("copyKernels", boolean(true));
+
+	fx.install(&fx.options()).unwrap();
+
+	let kernels = fs::read_dir(fx.root().join("boot/kernels"))
+		.unwrap()
+		.map(|e| e.unwrap().file_name().into_string().unwrap())
+		.collect::<Vec<_>>();
+	assert!(kernels.iter().any(|k| k.ends_with("bzImage.sig")));
+	assert!(kernels.iter().any(|k| k.ends_with("initrd.sig")));
+}
+
+#[test]
+fn check_signatures_needs_copied_kernels() {
+	let mut fx = Fixture::new();
+	fx.sign_config();
+	fx.set("checkSignatures", boolean(true));
+
+	let err = fx.install(&fx.options()).unwrap_err();
+
+	assert!(err.to_string().contains("copyKernels"));
+	assert!(fx.commands().is_empty());
+}
+
+#[test]
+fn check_signatures_without_signing_keeps_kernels_in_the_store() {
+	let mut fx = Fixture::new();
+	fx.set("checkSignatures", boolean(true));
+
+	fx.install(&fx.options()).unwrap();
+}
+
+/// Sets a font, splash image and theme, returning where they're installed.
+fn set_appearance(fx: &mut Fixture) -> [PathBuf; 3] {
+	let font = fx.root().join("font.pf2");
+	fs::write(&font, "font").unwrap();
+	fx.set("font", string(&font.to_string_lossy()));
+	let splash = fx.root().join("splash.png");
+	fs::write(&splash, "splash").unwrap();
+	fx.set("splashImage", string(&splash.to_string_lossy()));
+	let theme = fx.root().join("theme");
+	fs::create_dir_all(&theme).unwrap();
+	fs::write(theme.join("theme.txt"), "title-text: \"\"").unwrap();
+	fx.set("theme", string(&theme.to_string_lossy()));
+
+	["converted-font.pf2", "background.png", "theme/theme.txt"]
+		.map(|file| fx.root().join("boot").join(file))
+}
+
+fn sig(file: &Path) -> PathBuf {
+	let mut sig = file.as_os_str().to_owned();
+	sig.push(".sig");
+	PathBuf::from(sig)
+}
+
+#[test]
+fn signs_appearance_files() {
+	let mut fx = Fixture::new();
+	fx.sign_config();
+	let files = set_appearance(&mut fx);
+
+	fx.install(&fx.options()).unwrap();
+
+	for file in files {
+		assert!(file.exists());
+		assert!(file
+			.with_extension(format!(
+				"{}.sig",
+				file.extension().unwrap().to_string_lossy()
+			))
+			.exists());
+	}
+}
+
+#[test]
+fn removes_signatures_once_not_signing() {
+	let mut fx = Fixture::new();
+	fx.sign_config();
+	let files = set_appearance(&mut fx);
+	fx.install(&fx.options()).unwrap();
+
+	fx.unset("signConfig");
+	fx.install(&fx.options()).unwrap();
+
+	assert!(!fx.root().join("boot/grub/grub.cfg.sig").exists());
+	for file in files {
+		assert!(file.exists());
+		assert!(!sig(&file).exists());
+	}
+}
+
+#[test]
+fn failed_signing_restores_grub_cfg() {
+	let mut fx = Fixture::new();
+	fx.install(&fx.options()).unwrap();
+	let previous = fx.grub_cfg();
+
+	fx.sign_config();
+	fx.set("timeout", int(10));
+	fx.fail("gpg");
+
+	assert!(fx.install(&fx.options()).is_err());
+	assert_eq!(fx.grub_cfg(), previous);
+	assert!(!fx.root().join("boot/grub/grub.cfg.sig").exists());
+}
+
+#[test]
+fn theme_without_theme_txt_keeps_installed_theme() {
+	let mut fx = Fixture::new();
+	let installed = fx.root().join("boot/theme/theme.txt");
+	fs::create_dir_all(installed.parent().unwrap()).unwrap();
+	fs::write(&installed, "title-text: \"\"").unwrap();
+	let theme = fx.root().join("theme");
+	fs::create_dir_all(&theme).unwrap();
+	fs::write(theme.join("background.png"), "").unwrap();
+	fx.set("theme", string(&theme.to_string_lossy()));
+
+	let err = fx.install(&fx.options()).unwrap_err();
+
+	assert!(format!("{err:#}").contains("does not contain a theme.txt"));
+	assert!(installed.exists());
+}
+
+#[test]
+fn bios_target_detection() {
+	let mut fx = Fixture::new();
+	let grub = fx.root().join("store/dddd-grub-2.12");
+	fx.set("grub", string(&grub.to_string_lossy()));
+	fx.set("grubEfi", string(""));
+	let opts = InstallOptions {
+		force: true,
+		..fx.options()
+	};
+
+	for (require, ok) in [(false, true), (true, false)] {
+		fx.set("requireExplicitTarget", boolean(require));
+
+		assert_eq!(fx.install(&opts).is_ok(), ok);
+	}
+}
+
+#[test]
+fn restored_grub_cfg_keeps_its_mode() {
+	let mut fx = Fixture::new();
+	fx.set(
+		"users",
+		String::from(
+			"<attrs><attr name=\"root\"><attrs><attr name=\"password\"><string value=\"hunter2\" \
+			 /></attr></attrs></attr></attrs>",
+		),
+	);
+	fx.install(&fx.options()).unwrap();
+
+	fx.set("bootloaderId", string("NixOS-other"));
+	fx.fail("grub-install");
+	assert!(fx.install(&fx.options()).is_err());
+
+	let mode = fs::metadata(fx.root().join("boot/grub/grub.cfg"))
+		.unwrap()
+		.permissions()
+		.mode();
+	assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn plaintext_passwords_are_only_readable_by_root() {
+	let mut fx = Fixture::new();
+	fx.set(
+		"users",
+		String::from(
+			"<attrs><attr name=\"root\"><attrs><attr name=\"password\"><string value=\"hunter2\" \
+			 /></attr></attrs></attr></attrs>",
+		),
+	);
+	// Left over from an interrupted run.
+	let temp = fx.root().join("boot/grub/grub.cfg.tmp");
+	fs::create_dir_all(temp.parent().unwrap()).unwrap();
+	fs::write(&temp, "").unwrap();
+	fs::set_permissions(&temp, fs::Permissions::from_mode(0o644)).unwrap();
+
+	fx.install(&fx.options()).unwrap();
+
+	let mode = fs::metadata(fx.root().join("boot/grub/grub.cfg"))
+		.unwrap()
+		.permissions()
+		.mode();
+	assert_eq!(mode & 0o777, 0o600);
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use std::{
+	fs,
+	os::unix::fs::{MetadataExt, PermissionsExt},
+};
+
+use common::Fixture;
+
+#[test]
+fn generate_config_writes_nothing() {
+	let fx = Fixture::new();
+
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	assert!(cfg.contains("menuentry \"NixOS\""));
+	assert!(cfg.contains("submenu \"NixOS - All configurations\""));
+	assert!(cfg.contains("loglevel=4"));
+	assert!(!fx.root().join("boot/grub/grub.cfg").exists());
+	assert!(fx.commands().is_empty());
+}
+
+#[test]
+fn install_writes_grub_cfg_and_installs_once() {
+	let fx = Fixture::new();
+	let opts = fx.options();
+
+	fx.install(&opts).unwrap();
+
+	assert_eq!(fx.grub_cfg(), fx.generate(&opts).unwrap());
+	let installs = fx
+		.commands()
+		.into_iter()
+		.filter(|c| c.starts_with("grub-install"))
+		.count();
+	assert_eq!(installs, 1);
+
+	// Nothing changed, so GRUB isn't reinstalled.
+	fx.install(&opts).unwrap();
+	let installs = fx
+		.commands()
+		.into_iter()
+		.filter(|c| c.starts_with("grub-install"))
+		.count();
+	assert_eq!(installs, 1);
+}
+
+#[test]
+fn changing_a_secret_regenerates_grub_cfg() {
+	let fx = Fixture::new();
+	let opts = fx.options();
+	let secret = fx.root().join("secret");
+	fs::write(&secret, "hunter2\n").unwrap();
+	let script = fx
+		.root()
+		.join("store/cccc-nixos-system-test-24.05/append-initrd-secrets");
+	fs::write(
+		&script,
+		format!("#!/bin/sh\ncat {} >> \"$1\"\n", secret.display()),
+	)
+	.unwrap();
+	fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+	let conf = fx.root().join("boot/grub/grub.cfg");
+	let secrets = fx
+		.root()
+		.join("boot/kernels/cccc-nixos-system-test-24.05-secrets");
+
+	fx.install(&opts).unwrap();
+	let written = fs::metadata(&conf).unwrap().ino();
+
+	// Nothing about the config changed, only what's in the secrets initrd.
+	fs::write(&secret, "correct horse\n").unwrap();
+	fx.install(&opts).unwrap();
+
+	assert_ne!(fs::metadata(&conf).unwrap().ino(), written);
+	assert_eq!(fs::read_to_string(&secrets).unwrap(), "correct horse\n");
+}
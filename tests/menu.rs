@@ -0,0 +1,183 @@
+mod common;
+
+use std::{fs, os::unix::fs::symlink};
+
+use common::{boolean, int, string, Fixture};
+use install_grub::InstallOptions;
+
+// 2024-01-15 and 2024-02-15, at noon.
+const JANUARY: i64 = 1_705_320_000;
+const FEBRUARY: i64 = 1_707_998_400;
+
+#[test]
+fn groups_generations_by_month() {
+	let mut fx = Fixture::new();
+	fx.add_generation(2, JANUARY);
+	fx.add_generation(3, FEBRUARY);
+	fx.add_generation(4, JANUARY);
+	fx.set("groupGenerationsByMonth", boolean(true));
+	fx.set("generationSort", string("kernel-version"));
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	// Even though the kernels being the same leaves the generations in any
+	// order, each month only gets one submenu.
+	assert_eq!(cfg.matches("submenu \"2024-01\"").count(), 1);
+	assert_eq!(cfg.matches("submenu \"2024-02\"").count(), 1);
+	let february = cfg.find("submenu \"2024-02\"").unwrap();
+	let january = cfg.find("submenu \"2024-01\"").unwrap();
+	assert!(february < january);
+	assert!(cfg[january..].contains("Configuration 4 (2024-01-15"));
+	assert!(cfg[january..].contains("Configuration 2 (2024-01-15"));
+}
+
+#[test]
+fn default_by_title() {
+	let mut fx = Fixture::new();
+	fx.set("default", string("NixOS"));
+
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	assert!(cfg.contains("set default=\"NixOS\""));
+}
+
+#[test]
+fn default_by_title_in_a_submenu() {
+	let mut fx = Fixture::new();
+	fx.add_generation(2, JANUARY);
+	let submenu = "NixOS - All configurations>NixOS - Configuration 2 (2024-01-15 - 24.05)";
+
+	// By its title alone, or by the path to it.
+	for default in ["NixOS - Configuration 2 (2024-01-15 - 24.05)", submenu] {
+		fx.set("default", string(default));
+
+		let cfg = fx.generate(&fx.options()).unwrap();
+
+		assert!(cfg.contains(&format!("set default=\"{submenu}\"")));
+	}
+}
+
+#[test]
+fn default_by_title_needing_escapes() {
+	let mut fx = Fixture::new();
+	fx.set(
+		"quirkEntries",
+		String::from(
+			"<list><attrs><attr name=\"title\"><string value=\"&quot;Safe&quot; $mode\" \
+			 /></attr><attr name=\"params\"><string value=\"nomodeset\" /></attr></attrs></list>",
+		),
+	);
+	fx.set("default", string(r#"NixOS - "Safe" $mode"#));
+
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	assert!(cfg.contains(r#"set default="NixOS - Troubleshooting>NixOS - \"Safe\" \$mode""#));
+}
+
+#[test]
+fn default_by_index_or_id() {
+	let mut fx = Fixture::new();
+	fx.set("default", string("1>0"));
+	assert!(fx
+		.generate(&fx.options())
+		.unwrap()
+		.contains("set default=\"1>0\""));
+
+	fx.set(
+		"extraEntries",
+		string("menuentry \"Windows\" --id windows {\n  chainloader +1\n}"),
+	);
+	fx.set("default", string("windows"));
+	assert!(fx
+		.generate(&fx.options())
+		.unwrap()
+		.contains("set default=\"windows\""));
+}
+
+#[test]
+fn default_without_a_match() {
+	let mut fx = Fixture::new();
+	fx.set("default", string("Windows"));
+
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	// Left for GRUB to look for, which falls back to the first entry.
+	assert!(cfg.contains("set default=\"Windows\""));
+}
+
+#[test]
+fn default_added_by_os_prober() {
+	let mut fx = Fixture::new();
+	fx.set("useOSProber", boolean(true));
+	fx.set("default", string("Windows Boot Manager (on /dev/sda1)"));
+
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	assert!(cfg.contains("set default=\"Windows Boot Manager (on /dev/sda1)\""));
+}
+
+#[test]
+fn default_in_a_specialisation_submenu() {
+	let mut fx = Fixture::new();
+	fx.add_generation(2, JANUARY);
+	fx.add_specialisation("gaming");
+	let default = "NixOS - Configuration 2 - Default (2024-01-15 - 24.05)";
+	fx.set("default", string(default));
+
+	// The submenu is titled `> NixOS - Configuration 2 (...)`, which GRUB can't
+	// refer to, so it's left as is.
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	assert!(cfg.contains(&format!("set default=\"{default}\"")));
+}
+
+#[test]
+fn max_kernels_keeps_current_generation() {
+	let mut fx = Fixture::new();
+	fx.set("copyKernels", boolean(true));
+	let opts = InstallOptions {
+		max_kernels: Some(1),
+		..fx.options()
+	};
+	// Its kernel and initrd alone are over the limit.
+	let cfg = fx.generate(&opts).unwrap();
+
+	assert!(cfg.contains("menuentry \"NixOS\""));
+	assert!(cfg.contains("linux "));
+}
+
+#[test]
+fn specialisation_limit() {
+	let mut fx = Fixture::new();
+	for name in ["a", "b", "c"] {
+		fx.add_specialisation(name);
+	}
+	fx.set("specialisationLimit", int(2));
+
+	let cfg = fx.generate(&fx.options()).unwrap();
+
+	assert_eq!(cfg.matches("menuentry \"NixOS - Default - ").count(), 2);
+	assert!(!cfg.contains("menuentry \"NixOS - Default - (c "));
+}
+
+#[test]
+fn lists_why_generations_are_skipped() {
+	let fx = Fixture::new();
+	fx.add_generation(2, JANUARY);
+	// A generation whose system has lost its kernel, e.g. to a broken build.
+	let broken = fx.root().join("store/eeee-nixos-system-broken");
+	fs::create_dir_all(&broken).unwrap();
+	fs::write(broken.join("nixos-version"), "24.05").unwrap();
+	symlink(&broken, fx.root().join("profiles/system-3-link")).unwrap();
+	let opts = InstallOptions {
+		dry_run: true,
+		..fx.options()
+	};
+
+	let listing = fx.list_generations(&opts).unwrap();
+
+	assert!(listing.starts_with("system:\n"));
+	assert!(listing.contains("  3\t"));
+	assert!(listing.contains("\t24.05\tskipped: missing kernel\n"));
+	assert!(listing.contains("  2\t2024-01-15\t24.05\n"));
+	assert_eq!(listing.matches("skipped").count(), 1);
+}